@@ -14,7 +14,7 @@ import promql_parser
 promql = 'http_requests_total{environment=~"staging|testing|development",method!="GET"} offset 5m'
 expr = promql_parser.parse(promql)
 print(f"Prettify:\n{expr.prettify()}")
-print(f"AST:\n{expr}")
+print(f"AST:\n{expr!r}")
 ```
 
 This outputs:
@@ -24,7 +24,7 @@ Prettify:
 http_requests_total{environment=~"staging|testing|development",method!="GET"} offset 5m
 
 AST:
-VectorSelector(VectorSelector { name: Some("http_requests_total"), matchers: Matchers { matchers: [Matcher { op: Re(staging|testing|development), name: "environment", value: "staging|testing|development" }, Matcher { op: NotEqual, name: "method", value: "GET" }] }, offset: Some(Pos(300s)), at: None })
+VectorSelector(name="http_requests_total", matchers={environment=~"staging|testing|development",method!="GET"}, offset=5m)
 ```
 
 ## PromQL compliance
@@ -35,10 +35,99 @@ which isreleased at 2023-06-23. Any revision on PromQL after this commit is not
 
 from datetime import datetime, timedelta
 from enum import Enum
-from typing import Any, List, Optional, final
+from typing import Any, Callable, Iterator, List, Optional, Tuple, Union, final
+
+def parse(
+    input: Union[str, bytes, bytearray],
+    *,
+    max_length: Optional[int] = None,
+    max_matchers: Optional[int] = None,
+    desugar: bool = False,
+) -> Expr:
+    """Parse the given query literal to an AST.
+
+    `bytes` and `bytearray` are decoded as UTF-8; invalid UTF-8 raises `ValueError`.
+
+    The optional limits guard against abusive input and raise `ValueError`
+    naming the exceeded limit:
+
+    - `max_length`: the maximum number of characters, checked before parsing;
+    - `max_matchers`: the maximum number of label matchers across all selectors,
+      counting those in `or` groups, checked after parsing.
+
+    With `desugar=True`, equivalent spellings are collapsed into one form
+    without changing what the query evaluates to: parentheses that don't
+    affect precedence are removed, e.g. `((rate(foo[5m])))` becomes
+    `rate(foo[5m])` while `(a + b) * c` is kept, and so are zero offsets.
+    """
+    ...
+
+def parse_cached(input: Union[str, bytes, bytearray]) -> Expr:
+    """Like `parse`, but reuses the AST of recently parsed identical queries.
+
+    Results are kept in a bounded, thread-safe LRU cache keyed by the query
+    text, holding 128 queries by default. A cached query returns the same
+    `Expr` object as before, which is safe to share since AST nodes are
+    immutable. Invalid queries are not cached.
+    """
+    ...
+
+def set_parse_cache_size(size: int) -> None:
+    """Set how many queries `parse_cached` keeps, evicting the least recently used.
+
+    A size of 0 disables caching.
+    """
+    ...
+
+def clear_parse_cache() -> None:
+    """Drop all queries cached by `parse_cached`."""
+    ...
+
+def diff(a: Expr, b: Expr) -> List[str]:
+    """Describe how `b` differs from `a`, walking both trees in parallel.
+
+    Each difference is reported as `<path>: <change>`, where the path names
+    the node as reached from Python starting at `expr`:
 
-def parse(input: str) -> Expr:
-    """Parse the given query literal to an AST."""
+    ```python
+    >>> promql_parser.diff(parse('rate(foo{job="api"}[5m])'), parse('rate(foo{job="web"}[10m])'))
+    ['expr.args[0]: range changed from 5m to 10m',
+     'expr.args[0].vector_selector: matcher job changed from job="api" to job="web"']
+    ```
+
+    Nodes of different types, such as a selector replaced by a function call,
+    are reported once as replaced without comparing their children. An empty
+    list means the trees are equal.
+    """
+    ...
+
+def parse_lines(text: str) -> ParseLines:
+    """Parse a text holding one query per line, lazily.
+
+    Yields `(line_number, result)` for each line, where `line_number` starts
+    at 1 and `result` is the parsed `Expr`, or the `ValueError` raised for an
+    invalid query instead of raising it. Blank lines and lines starting with
+    `#` are skipped; surrounding whitespace is ignored.
+
+    ```python
+    import promql_parser
+
+    for lineno, result in promql_parser.parse_lines(open("queries.txt").read()):
+        if isinstance(result, Exception):
+            print(f"line {lineno}: {result}")
+    ```
+    """
+    ...
+
+def tokenize(input: Union[str, bytes, bytearray]) -> List[Token]:
+    """Split the given query into tokens without parsing it.
+
+    This works on input that is not a complete expression, including
+    parentheses left open at the end, e.g. `sum(rate(foo[5m]`, which makes it
+    suitable for syntax highlighting in editors. Whitespace and comments are
+    skipped, and no token marks the end of input. Raises `ValueError` on other
+    lexical errors, e.g. an unterminated string or an unclosed `{` or `[`.
+    """
     ...
 
 def parse_duration(duration: str) -> timedelta:
@@ -64,10 +153,268 @@ def display_duration(delta: timedelta) -> str:
     """Display Duration in Prometheus format"""
     ...
 
+def duration_to_promql(delta: timedelta) -> str:
+    """Format a timedelta as a PromQL duration, the same way ranges and offsets are rendered.
+
+    Accepts any non-negative timedelta, not only ones produced by `parse_duration`.
+    Zero renders as `0s` and precision below a millisecond is dropped.
+    Raises `ValueError` for negative durations and for non-zero ones under 1ms.
+
+    ```python
+    from datetime import timedelta
+    import promql_parser
+
+    assert promql_parser.duration_to_promql(timedelta(minutes=90)) == "1h30m"
+    assert promql_parser.duration_to_promql(timedelta(days=8)) == "1w1d"
+    ```
+    """
+    ...
+
 class Expr:
     @staticmethod
-    def parse(input: str) -> Any: ...
-    def prettify(self) -> str: ...
+    def parse(
+        input: Union[str, bytes, bytearray],
+        *,
+        max_length: Optional[int] = None,
+        max_matchers: Optional[int] = None,
+        desugar: bool = False,
+    ) -> Any:
+        """Same as `promql_parser.parse`."""
+        ...
+    @staticmethod
+    def parse_vector(input: Union[str, bytes, bytearray]) -> Expr:
+        """Parse the query and check that it evaluates to an instant vector.
+
+        Raises `ValueError` naming the actual result type otherwise.
+        """
+        ...
+    @staticmethod
+    def parse_scalar(input: Union[str, bytes, bytearray]) -> Expr:
+        """Parse the query and check that it evaluates to a scalar.
+
+        Raises `ValueError` naming the actual result type otherwise.
+        """
+        ...
+    def kind(self) -> str:
+        """A stable tag naming the node type, for dispatching without `isinstance`.
+
+        One of `"Aggregate"`, `"Unary"`, `"Binary"`, `"Paren"`, `"Subquery"`,
+        `"NumberLiteral"`, `"StringLiteral"`, `"VectorSelector"`,
+        `"MatrixSelector"`, `"Call"` or `"Extension"`.
+        """
+        ...
+    def value_type(self) -> ValueType:
+        """The type the expression evaluates to."""
+        ...
+    def is_instant_vector(self) -> bool:
+        """Whether `value_type()` is `ValueType.Vector`, e.g. `foo` or `rate(foo[5m])`."""
+        ...
+    def is_range_vector(self) -> bool:
+        """Whether `value_type()` is `ValueType.Matrix`, e.g. `foo[5m]`."""
+        ...
+    def is_scalar(self) -> bool:
+        """Whether `value_type()` is `ValueType.Scalar`."""
+        ...
+    def is_string(self) -> bool:
+        """Whether `value_type()` is `ValueType.String`."""
+        ...
+    def explain(self) -> str:
+        """Describe the query in plain English, for readers who don't know PromQL.
+
+        Each node type is described with a fixed template, so the result is
+        deterministic but not always elegant prose:
+
+        ```python
+        >>> parse("sum by (job) (rate(http_requests_total[5m]))").explain()
+        '5m rate of http_requests_total, summed by job'
+        ```
+        """
+        ...
+    def prettify(self) -> str:
+        """Format the expression as PromQL, splitting long expressions over several lines.
+
+        Every node owns its subtree, so this works on any child as well, e.g.
+        `parse("a + b").lhs.prettify() == "a"`.
+        """
+        ...
+    def __str__(self) -> str:
+        """Render the expression back to PromQL on a single line.
+
+        String literals and matcher values are re-quoted with escaping, so the
+        result parses back to an equal expression.
+        """
+        ...
+    def __repr__(self) -> str:
+        """A concise representation built from the node's public fields.
+
+        Its format doesn't depend on the Rust `promql-parser` crate, so it stays
+        stable across dependency upgrades. Unset optional fields are omitted.
+        """
+        ...
+    def __eq__(self, other: object) -> bool:
+        """Whether both trees are structurally equal.
+
+        The comparison is strict: matchers must be written in the same order,
+        and `1m` equals `60s` but `(foo)` does not equal `foo`.
+        """
+        ...
+    def __hash__(self) -> int: ...
+    def equals_query(self, text: Union[str, bytes, bytearray]) -> bool:
+        """Whether parsing `text` yields an expression equal to this one.
+
+        Returns `False` rather than raising if `text` is not valid PromQL, so
+        it reads naturally in assertions: `assert expr.equals_query("sum(x)")`.
+        """
+        ...
+    def debug_repr(self) -> str:
+        """The full Rust `Debug` dump of the underlying AST, for troubleshooting."""
+        ...
+    def canonical(self) -> str:
+        """Render the expression in a canonical form, e.g. for use as a cache key.
+
+        Queries that differ only in the following render to the same string:
+
+        - whitespace, and the order of `{...}` and modifiers that PromQL allows
+          writing in more than one place;
+        - the order of matchers in a selector, both within and across `or` groups;
+        - the order of labels in `by`/`without`, `on`/`ignoring` and
+          `group_left`/`group_right`, with duplicates removed;
+        - an empty `by ()` on an aggregation or `ignoring ()` on a binary
+          operator, which is dropped;
+        - duration spelling, e.g. `60s` and `1m`, and number spelling, e.g. `1e3`
+          and `1000`;
+        - string quoting and escaping, e.g. `'a'` and `"a"`.
+
+        Parentheses are kept as written. The result is idempotent:
+        `parse(q.canonical()).canonical() == q.canonical()`.
+        """
+        ...
+    def without_time_modifiers(self) -> Expr:
+        """A copy of the expression with every `@` and `offset` modifier removed.
+
+        Applies to all selectors and subqueries in the tree; `self` is left unchanged.
+        """
+        ...
+    def with_at(self, timestamp: datetime) -> Expr:
+        """A copy of the expression pinned to `timestamp` with `@` modifiers.
+
+        The modifier is set on every selector and subquery that is not nested
+        in a subquery, replacing any `@` already there; selectors inside a
+        subquery keep being evaluated at the subquery's steps.
+        Raises `ValueError` if `timestamp` is naive or before the Unix epoch.
+        """
+        ...
+    def deprecated_functions(self) -> List[str]:
+        """Names of deprecated or experimental functions called in the tree.
+
+        Each name is listed once, in order of first use. The list covers
+        `holt_winters` and the experimental native histogram functions
+        `histogram_count`, `histogram_sum` and `histogram_fraction`.
+        """
+        ...
+    def uses_histogram_functions(self) -> bool:
+        """Whether a histogram function is called anywhere in the tree.
+
+        The family is `histogram_quantile`, `histogram_count`, `histogram_sum`
+        and `histogram_fraction`, whether applied to classic or native
+        histograms.
+        """
+        ...
+    def label_names_used(self) -> List[str]:
+        """Label names referenced anywhere in the tree, sorted and deduplicated.
+
+        Covers the names of label matchers, `by`/`without` labels,
+        `on`/`ignoring` labels and `group_left`/`group_right` labels. A bare
+        metric name such as `up` counts as a `__name__` matcher, so `up` and
+        `{__name__="up"}` both report `__name__`.
+        """
+        ...
+    def is_simple_selector(self) -> bool:
+        """Whether the query is nothing but an instant vector selector, e.g. `up{job="api"}`.
+
+        Only the top node is checked: a range selector such as `up[5m]` or a
+        parenthesized selector such as `(up)` is not considered simple.
+        `@` and `offset` modifiers are allowed.
+        """
+        ...
+    def drops_metric_name(self) -> bool:
+        """Whether the series resulting from the expression lack a metric name.
+
+        Follows the rules Prometheus applies during evaluation: selectors keep
+        `__name__`, as do `topk`/`bottomk`, aggregations `by (__name__)`,
+        comparisons without `bool`, the set operators and the functions
+        `last_over_time`, `label_join`, `label_replace`, `sort` and `sort_desc`
+        when their input keeps it. Arithmetic, unary minus, other aggregations
+        and other functions drop it. Scalars and strings have no metric name,
+        so this is `True` for them.
+        """
+        ...
+    def find_all(self, node_type: str) -> List[Expr]:
+        """All nodes in the tree whose class name matches `node_type`, in pre-order.
+
+        `node_type` is a class name such as `"Call"` or `"SubqueryExpr"`, and
+        may use the shell-style wildcards `*` and `?`, e.g. `"*Selector"`.
+        The `vector_selector` of a `MatrixSelector` is included when matching
+        `VectorSelector`. Raises `ValueError` listing the valid names if
+        `node_type` matches no node class.
+        """
+        ...
+    def any(self, predicate: Callable[[Expr], Any]) -> bool:
+        """Whether `predicate` is true for any node in the tree.
+
+        Nodes are visited in pre-order, as by `find_first`, and traversal stops
+        at the first match, e.g. `expr.any(lambda n: isinstance(n, SubqueryExpr))`.
+        """
+        ...
+    def find_first(self, predicate: Callable[[Expr], Any]) -> Optional[Expr]:
+        """The first node in pre-order for which `predicate` is true, or `None`.
+
+        The expression itself is visited first, and the `vector_selector` of a
+        `MatrixSelector` right after it. Traversal stops at the first match;
+        an exception raised by `predicate` is propagated.
+        """
+        ...
+    def vector_selectors(self) -> List[VectorSelector]:
+        """Every `VectorSelector` in the tree, in pre-order.
+
+        The `vector_selector` of each `MatrixSelector` is included, so
+        `rate(foo[5m])` yields the selector `foo`.
+        """
+        ...
+    def selectors(self) -> List[Tuple[Optional[str], Matchers]]:
+        """Every vector and range selector in the tree as a `(metric, matchers)` pair.
+
+        The metric is taken from `{__name__="..."}` when the selector has no
+        bare name, as by `VectorSelector.metric_name`, and is `None` if the
+        selector doesn't pin one. Selectors are listed in pre-order, including
+        those in subqueries and function arguments.
+        """
+        ...
+    def rename_metric(self, old: str, new: str) -> Expr:
+        """A copy of the expression with every selector of metric `old` selecting `new`.
+
+        Both bare names like `old{job="api"}` and `{__name__="old"}` matchers
+        are rewritten, in every selector of the tree; regex matchers on
+        `__name__` are left alone. Raises `ValueError` if `new` is not a valid
+        metric name.
+        """
+        ...
+    def node_count(self) -> int:
+        """The total number of nodes in the tree, including this one."""
+        ...
+    def complexity(self) -> int:
+        """A rough cost estimate of evaluating the expression.
+
+        Every node counts 1, except matrix selectors, which count 5, and
+        subqueries, which count 10, since they read much more data.
+        """
+        ...
+    def contains_at_modifier(self) -> bool:
+        """Whether any selector or subquery in the tree carries an `@` modifier."""
+        ...
+    def contains_offset(self) -> bool:
+        """Whether any selector or subquery in the tree carries an `offset` modifier."""
+        ...
 
 @final
 class AggregateExpr(Expr):
@@ -75,12 +422,17 @@ class AggregateExpr(Expr):
 
     Attributes:
       op: The used aggregation operation.
+      op_name: The aggregation keyword, e.g. `"sum"` or `"topk"`.
       expr: The Vector expression over which is aggregated.
       param: Parameter used by some aggregators.
       modifier: An optional modifier for some operations like sum.
+        It is `None` for `sum(x)`, and an `AggModifier` with empty `labels`
+        for `sum by () (x)` or `sum without () (x)`, so the three forms can
+        be told apart.
     """
 
     op: TokenType
+    op_name: str
     expr: Expr
     param: Optional[Any]
     modifier: Optional[AggModifier]
@@ -89,8 +441,31 @@ class AggregateExpr(Expr):
 class TokenType:
     pass
 
+@final
+class Token:
+    """A lexical token of a query.
+
+    Attributes:
+      kind: The type of the token.
+      text: The source text of the token.
+      start: The byte offset at which the token starts.
+      end: The byte offset just past the end of the token.
+    """
+
+    kind: TokenType
+    text: str
+    start: int
+    end: int
+
 @final
 class AggModifier:
+    """The `by`/`without` clause of an aggregation.
+
+    Attributes:
+      type: Whether labels are kept (`by`) or dropped (`without`).
+      labels: The label names as plain strings, in the order written.
+    """
+
     type: AggModifierType
     labels: List[str]
 
@@ -111,16 +486,43 @@ class BinaryExpr(Expr):
 
     Attributes:
       op: The operation of the expression.
+      op_name: The operator symbol or keyword, e.g. `"+"`, `"=="` or `"and"`.
       lhs: The operands on the left side of the operator.
       rhs: The operands on the right side of the operator.
       modifier: An optional modifier.
     """
 
     op: TokenType
+    op_name: str
     lhs: Expr
     rhs: Expr
     modifier: Optional[BinModifier]
 
+    def __init__(
+        self,
+        op: str,
+        lhs: Expr,
+        rhs: Expr,
+        modifier: Optional[BinModifier] = None,
+        *,
+        return_bool: bool = False,
+    ) -> None:
+        """Combine two expressions with an operator, e.g. `BinaryExpr("/", a, b)`.
+
+        `op` is the operator as written in PromQL, such as `"+"`, `">="` or
+        `"and"`. `modifier` can be taken from another `BinaryExpr` to reuse its
+        vector matching, and `return_bool` adds the `bool` modifier.
+
+        Operands that are binary expressions themselves, and a unary expression
+        on the left, are wrapped in parentheses so the result renders to
+        PromQL that parses back to an equal expression.
+
+        The expression is validated like a parsed query, so e.g. comparing two
+        scalars without `bool`, or vector matching between scalars, raises
+        `ValueError`.
+        """
+        ...
+
 @final
 class BinModifier:
     """Binary expression modifier
@@ -137,6 +539,13 @@ class BinModifier:
     matching: Optional[LabelModifier]
     return_bool: bool
 
+    def on_labels(self) -> List[str]:
+        """Labels listed in `on(...)`, empty unless matching is `on`."""
+        ...
+    def ignoring_labels(self) -> List[str]:
+        """Labels listed in `ignoring(...)`, empty unless matching is `ignoring`."""
+        ...
+
 @final
 class LabelModifier:
     """LabelModifier acts as
@@ -154,6 +563,8 @@ class LabelModifier:
     even if their label values are identical between all elements of the vector.
 
     If empty listed labels, meaning no grouping
+
+    Label names are plain strings, like `Matcher.name`.
     """
 
     type: LabelModifierType
@@ -182,7 +593,13 @@ class ParenExpr(Expr):
 
 @final
 class SubqueryExpr(Expr):
-    """A subquery."""
+    """A subquery.
+
+    `offset` is `None` rather than zero when the subquery has no offset.
+    Durations too large for `timedelta` are clamped to `timedelta.max`, or
+    to `timedelta.min` for a negative offset; `str()` still renders them as
+    written.
+    """
 
     expr: Expr
     offset: Optional[timedelta]
@@ -190,11 +607,43 @@ class SubqueryExpr(Expr):
     range: Optional[timedelta]
     step: Optional[timedelta]
 
+    def effective_step(self, default: timedelta) -> timedelta:
+        """The resolution the subquery is evaluated at.
+
+        This is `step` if the query sets one, and otherwise `default`, which
+        should be the global evaluation interval as Prometheus falls back to it.
+        Raises `ValueError` if `default` is not positive.
+        """
+        ...
+
 @final
 class AtModifier:
     type: AtModifierType
     at: Optional[datetime]
 
+    @staticmethod
+    def from_datetime(timestamp: datetime) -> AtModifier:
+        """`@ <timestamp>`, pinning evaluation to `timestamp`.
+
+        Named `from_datetime` since `at` is the attribute holding the time.
+        Raises `ValueError` if `timestamp` is naive or before the Unix epoch.
+        """
+        ...
+    @staticmethod
+    def start() -> AtModifier:
+        """`@ start()`, evaluating at the start of the query range."""
+        ...
+    @staticmethod
+    def end() -> AtModifier:
+        """`@ end()`, evaluating at the end of the query range."""
+        ...
+    def timestamp(self) -> Optional[float]:
+        """The pinned time in seconds since the Unix epoch, including the fraction.
+
+        Negative for times before the epoch. `None` for `start()` and `end()`.
+        """
+        ...
+
 @final
 class AtModifierType(Enum):
     Start: Any
@@ -203,18 +652,37 @@ class AtModifierType(Enum):
 
 @final
 class NumberLiteral(Expr):
-    """A number literal."""
+    """A number literal.
+
+    Only the value is kept, not the text as written: `0x10`, `1.6e1` and `16`
+    all parse to the same literal, which `str()` renders compactly as `16`.
+    Very large or small magnitudes use exponent notation, e.g. `1e300`.
+    """
 
     val: float
 
 @final
 class StringLiteral(Expr):
-    """A string literal."""
+    """A string literal.
+
+    Attributes:
+      val: The string as written between the quotes, with escape sequences
+        left as is, e.g. `a\\"b` for `"a\\"b"`. Like `Matcher.value`.
+      unescaped_val: The string value with escape sequences interpreted,
+        e.g. `a"b` for `"a\\"b"`. Like `Matcher.unescaped_value`.
+    """
 
     val: str
+    unescaped_val: str
 
 @final
 class MatchOp(Enum):
+    """The operator of a label matcher.
+
+    Members compare equal by value and hash consistently, so they can be used
+    as dict keys, e.g. to group matchers by operator.
+    """
+
     Equal: Any
     NotEqual: Any
     Re: Any
@@ -222,30 +690,133 @@ class MatchOp(Enum):
 
 @final
 class Matcher:
+    """A single label matcher, e.g. `job="api"`.
+
+    Matchers are hashable and compare equal when op, name and value match.
+
+    Attributes:
+      value: The value as written between the quotes, with escape sequences
+        left as is, e.g. `a\\"b` for `job="a\\"b"`.
+      unescaped_value: The value with escape sequences interpreted, e.g. `a"b`.
+    """
+
     op: MatchOp
     name: str
     value: str
+    unescaped_value: str
 
 @final
 class Matchers:
+    """The label matchers of a selector.
+
+    Matchers compare equal and hash the same regardless of the order in which
+    they are written, and duplicates are ignored. `or_matchers` is compared
+    as a set of sets: neither the order of the `or` groups nor the order of
+    matchers within a group matters.
+
+    Attributes:
+      matchers: The matchers of a selector without `or`.
+      or_matchers: The groups of a selector using `or`, e.g. `{a="1" or b="2"}`.
+    """
+
     matchers: List[Matcher]
     or_matchers: List[List[Matcher]]
 
+    def as_set(self) -> set[Matcher]:
+        """The plain `matchers` as a set, for set operations between selectors."""
+        ...
+    def or_as_set(self) -> set[frozenset[Matcher]]:
+        """The `or_matchers` as a set of frozensets, one per `or` group."""
+        ...
+    def by_op(self, op: MatchOp) -> List[Matcher]:
+        """The plain `matchers` using `op`, in the order they are written.
+
+        `or_matchers` are not included; filter each group of `or_matchers`
+        separately if needed.
+        """
+        ...
+    def conflicts_with(self, other: Matchers) -> bool:
+        """Whether no series can match both `self` and `other`.
+
+        Only contradicting `=` and `!=` matchers on the same label are detected,
+        e.g. `tenant="a"` against `tenant="b"`, or `tenant="a"` against
+        `tenant!="a"`; regex matchers are never considered conflicting. With
+        `or` groups, the sets conflict only if every pair of groups does.
+        """
+        ...
+    def to_promql(self) -> str:
+        """Render the matchers in braces, e.g. `{job="api"}` for `up{job="api"}`.
+
+        `or` groups are separated by ` or `. The metric name is not included.
+        """
+        ...
+
 @final
 class VectorSelector(Expr):
-    """A Vector selection."""
+    """A Vector selection.
+
+    `offset` is `None` rather than zero when the selector has no offset;
+    `offset 0s` is not valid PromQL. An offset too large for `timedelta` is
+    clamped to `timedelta.max`, or to `timedelta.min` if negative.
+    """
 
     name: Optional[str]
     matchers: Matchers
     offset: Optional[timedelta]
     at: Optional[AtModifier]
 
+    def __init__(
+        self,
+        name: Optional[str] = None,
+        matchers: Optional[Matchers] = None,
+        *,
+        offset: Optional[timedelta] = None,
+        at: Optional[AtModifier] = None,
+    ) -> None:
+        """Build a selector, e.g. `VectorSelector("foo", offset=timedelta(minutes=-5))`.
+
+        `matchers` can be taken from another selector. A negative `offset`
+        shifts the selector forward in time (`offset -5m`), and a zero `offset`
+        is the same as none. Raises `ValueError` if the selector isn't valid
+        PromQL, e.g. without a name or any matcher.
+        """
+        ...
+    def metric_name(self) -> Optional[str]:
+        """The metric selected, whether written as `foo` or as `{__name__="foo"}`.
+
+        `None` if the selector doesn't pin the metric name with an equality
+        matcher, e.g. `{job="api"}` or `{__name__=~"foo.*"}`.
+        """
+        ...
+
 @final
 class MatrixSelector(Expr):
-    """A Matrix selection."""
+    """A Matrix selection.
+
+    `name`, `matchers`, `offset` and `at` are shortcuts for the same
+    attributes of `vector_selector`. A `range` too large for `timedelta`,
+    e.g. `[10000000y]`, is clamped to `timedelta.max`; `str()` still
+    renders it as written.
+    """
 
     vector_selector: VectorSelector
     range: timedelta
+    name: Optional[str]
+    matchers: Matchers
+    offset: Optional[timedelta]
+    at: Optional[AtModifier]
+
+    def metric_name(self) -> Optional[str]:
+        """Same as `VectorSelector.metric_name` of `vector_selector`."""
+        ...
+
+    def __init__(self, vector_selector: VectorSelector, range: timedelta) -> None:
+        """Wrap a vector selector in a range, e.g. `foo{bar="baz"}[5m]`.
+
+        Raises `ValueError` if `range` is not positive or is not a whole
+        number of milliseconds.
+        """
+        ...
 
 @final
 class Call(Expr):
@@ -254,6 +825,24 @@ class Call(Expr):
     func: Function
     args: List[Any]
 
+@final
+class ExtensionExpr(Expr):
+    """A node produced by a parser extension rather than by PromQL itself.
+
+    The node is opaque: only its name and child expressions are exposed, and
+    `value_type()` reports what the extension declares. There is no PromQL
+    for the node, so `str()` falls back to the Rust debug output of the
+    extension, e.g. `Extension { expr: ... }`, which can't be parsed back.
+    `promql_parser.parse` never produces this node.
+
+    Attributes:
+      name: The name the extension gives to the node.
+      children: The child expressions of the node.
+    """
+
+    name: str
+    children: List[Expr]
+
 @final
 class ValueType(Enum):
     Vector: Any
@@ -267,3 +856,10 @@ class Function:
     arg_types: List[ValueType]
     variadic: bool
     return_type: ValueType
+
+@final
+class ParseLines(Iterator[Tuple[int, Union[Expr, Exception]]]):
+    """The iterator returned by `parse_lines`."""
+
+    def __iter__(self) -> ParseLines: ...
+    def __next__(self) -> Tuple[int, Union[Expr, Exception]]: ...
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use promql::parser;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::expr::PyExpr;
+
+/// Number of queries `parse_cached` keeps unless configured otherwise.
+const DEFAULT_CAPACITY: usize = 128;
+
+/// A bounded cache of parsed queries, evicting the least recently used.
+///
+/// The Python objects are cached rather than the Rust trees: AST nodes are
+/// immutable, so handing out the same object again is safe and skips the
+/// conversion.
+struct ParseCache {
+    capacity: usize,
+    /// Incremented on every access, recorded per entry to order them by use.
+    clock: u64,
+    entries: HashMap<String, (u64, PyObject)>,
+    /// The input of every entry keyed by its last use, oldest first.
+    recency: BTreeMap<u64, String>,
+}
+
+impl ParseCache {
+    fn get(&mut self, py: Python, input: &str) -> Option<PyObject> {
+        let (used, expr) = self.entries.get_mut(input)?;
+        let input = self
+            .recency
+            .remove(&*used)
+            .expect("every entry is in the recency queue");
+        self.clock += 1;
+        *used = self.clock;
+        self.recency.insert(self.clock, input);
+        Some(expr.clone_ref(py))
+    }
+
+    fn insert(&mut self, input: &str, expr: PyObject) {
+        if self.capacity == 0 {
+            return;
+        }
+        // Concurrent misses for the same input may both insert it.
+        if let Some((used, _)) = self.entries.remove(input) {
+            self.recency.remove(&used);
+        }
+        self.shrink_to(self.capacity - 1);
+        self.clock += 1;
+        self.entries.insert(input.to_string(), (self.clock, expr));
+        self.recency.insert(self.clock, input.to_string());
+    }
+
+    fn shrink_to(&mut self, len: usize) {
+        while self.entries.len() > len {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+fn cache() -> &'static Mutex<ParseCache> {
+    static CACHE: OnceLock<Mutex<ParseCache>> = OnceLock::new();
+    CACHE.get_or_init(|| {
+        Mutex::new(ParseCache {
+            capacity: DEFAULT_CAPACITY,
+            clock: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        })
+    })
+}
+
+fn with_cache<R>(f: impl FnOnce(&mut ParseCache) -> R) -> R {
+    f(&mut cache().lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+/// Parse `input`, returning the same object as an earlier call with the same
+/// input if it is still cached.
+///
+/// The lock isn't held while parsing, so concurrent misses for the same input
+/// may both parse it. Errors are not cached.
+pub fn parse(py: Python, input: &str) -> PyResult<PyObject> {
+    if let Some(expr) = with_cache(|cache| cache.get(py, input)) {
+        return Ok(expr);
+    }
+    let expr = parser::parse(input).map_err(PyValueError::new_err)?;
+    let expr = PyExpr::create(py, expr)?;
+    with_cache(|cache| cache.insert(input, expr.clone_ref(py)));
+    Ok(expr)
+}
+
+pub fn set_capacity(capacity: usize) {
+    with_cache(|cache| {
+        cache.capacity = capacity;
+        cache.shrink_to(capacity);
+    });
+}
+
+pub fn clear() {
+    with_cache(|cache| {
+        cache.entries.clear();
+        cache.recency.clear();
+    });
+}
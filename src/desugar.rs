@@ -0,0 +1,110 @@
+use promql::parser::{
+    AggregateExpr, BinaryExpr, Call, Expr, NumberLiteral, Offset, ParenExpr, SubqueryExpr,
+    UnaryExpr, VectorSelector,
+};
+
+/// Collapse equivalent spellings of `expr` into one form, without changing
+/// what it evaluates to:
+///
+/// - parentheses are removed where they don't affect precedence, i.e. around
+///   the whole query, function arguments, aggregation operands and atoms
+///   such as selectors, calls and aggregations;
+/// - zero offsets are removed.
+pub fn desugar(expr: &mut Expr) {
+    desugar_at(expr, true);
+}
+
+/// `delimited` is whether the position of `expr` is bounded by the
+/// surrounding syntax, such as a function argument, so that parentheses
+/// around it are never needed.
+fn desugar_at(expr: &mut Expr, delimited: bool) {
+    while let Expr::Paren(ParenExpr { expr: inner }) = expr {
+        if !delimited && !is_atom(inner) {
+            break;
+        }
+        let placeholder = Expr::NumberLiteral(NumberLiteral { val: 0.0 });
+        let inner = std::mem::replace(inner.as_mut(), placeholder);
+        *expr = inner;
+    }
+    match expr {
+        Expr::Aggregate(AggregateExpr { expr, param, .. }) => {
+            if let Some(param) = param {
+                desugar_at(param, true);
+            }
+            desugar_at(expr, true);
+        }
+        Expr::Call(Call { args, .. }) => {
+            for arg in &mut args.args {
+                desugar_at(arg, true);
+            }
+        }
+        Expr::Paren(ParenExpr { expr }) => desugar_at(expr, true),
+        Expr::Unary(UnaryExpr { expr }) => desugar_at(expr, false),
+        Expr::Binary(BinaryExpr { lhs, rhs, .. }) => {
+            desugar_at(lhs, false);
+            desugar_at(rhs, false);
+        }
+        Expr::Subquery(SubqueryExpr { expr, offset, .. }) => {
+            strip_zero_offset(offset);
+            desugar_at(expr, false);
+        }
+        Expr::VectorSelector(vs) => strip_zero_offset(&mut vs.offset),
+        Expr::MatrixSelector(ms) => strip_zero_offset(&mut ms.vs.offset),
+        Expr::NumberLiteral(_) | Expr::StringLiteral(_) | Expr::Extension(_) => (),
+    }
+}
+
+/// Whether `expr` binds tighter than any operator, so parentheses around it
+/// are redundant wherever it appears.
+pub fn is_atom(expr: &Expr) -> bool {
+    match expr {
+        Expr::VectorSelector(VectorSelector { offset, at, .. }) => offset.is_none() && at.is_none(),
+        Expr::NumberLiteral(NumberLiteral { val }) => !val.is_sign_negative(),
+        Expr::Paren(_) | Expr::Call(_) | Expr::Aggregate(_) | Expr::StringLiteral(_) => true,
+        _ => false,
+    }
+}
+
+/// Drop a zero offset, which Prometheus treats the same as no offset.
+pub fn strip_zero_offset(offset: &mut Option<Offset>) {
+    if let Some(Offset::Pos(d) | Offset::Neg(d)) = offset {
+        if d.is_zero() {
+            *offset = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use promql::parser::{self, Expr, MatrixSelector, Offset, VectorSelector};
+
+    use super::desugar;
+
+    #[test]
+    fn strips_zero_offsets() {
+        // The parser rejects `offset 0s`, so the zero offset is built by hand.
+        let mut vs = VectorSelector::from("foo".to_string());
+        vs.offset = Some(Offset::Neg(Duration::ZERO));
+        let mut expr = Expr::MatrixSelector(MatrixSelector {
+            vs,
+            range: Duration::from_secs(300),
+        });
+        desugar(&mut expr);
+        let Expr::MatrixSelector(ms) = expr else {
+            panic!("desugaring kept a matrix selector at the top");
+        };
+        assert!(ms.vs.offset.is_none());
+    }
+
+    #[test]
+    fn keeps_nonzero_offsets() {
+        let mut expr = parser::parse("(foo offset 5m)").unwrap();
+        desugar(&mut expr);
+        let Expr::VectorSelector(vs) = expr else {
+            panic!("parentheses around the selector were kept");
+        };
+        assert_eq!(vs.offset, Some(Offset::Pos(Duration::from_secs(300))));
+    }
+}
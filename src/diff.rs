@@ -0,0 +1,305 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use promql::label::Matchers;
+use promql::parser::{
+    AggregateExpr, AtModifier, BinModifier, BinaryExpr, Call, Expr, LabelModifier, MatrixSelector,
+    NumberLiteral, ParenExpr, StringLiteral, SubqueryExpr, UnaryExpr, VectorSelector,
+};
+use promql::util::display_duration;
+
+use crate::format::{self, Promql};
+
+/// Compare two trees node by node and describe each difference as
+/// `<path>: <change>`, where the path names the node from the root `expr`
+/// the way it is reached from Python, e.g. `expr.lhs.args[0]`.
+///
+/// Nodes of different types are reported once as replaced, without looking
+/// into their children.
+pub fn diff(a: &Expr, b: &Expr) -> Vec<String> {
+    let mut changes = Vec::new();
+    diff_at("expr", a, b, &mut changes);
+    changes
+}
+
+fn diff_at(path: &str, a: &Expr, b: &Expr, out: &mut Vec<String>) {
+    match (a, b) {
+        (
+            Expr::Aggregate(AggregateExpr {
+                op: op_a,
+                expr: expr_a,
+                param: param_a,
+                modifier: modifier_a,
+            }),
+            Expr::Aggregate(AggregateExpr {
+                op: op_b,
+                expr: expr_b,
+                param: param_b,
+                modifier: modifier_b,
+            }),
+        ) => {
+            if op_a != op_b {
+                push(out, path, changed("operator", op_a, op_b));
+            }
+            if modifier_a != modifier_b {
+                let grouping = |m: &Option<LabelModifier>| match m {
+                    Some(LabelModifier::Include(ls)) => format!("by ({ls})"),
+                    Some(LabelModifier::Exclude(ls)) => format!("without ({ls})"),
+                    None => "none".to_string(),
+                };
+                push(
+                    out,
+                    path,
+                    changed("grouping", grouping(modifier_a), grouping(modifier_b)),
+                );
+            }
+            match (param_a, param_b) {
+                (Some(a), Some(b)) => diff_at(&format!("{path}.param"), a, b, out),
+                (None, None) => (),
+                _ => push(
+                    out,
+                    path,
+                    changed("parameter", optional(param_a), optional(param_b)),
+                ),
+            }
+            diff_at(&format!("{path}.expr"), expr_a, expr_b, out);
+        }
+        (Expr::Unary(UnaryExpr { expr: a }), Expr::Unary(UnaryExpr { expr: b }))
+        | (Expr::Paren(ParenExpr { expr: a }), Expr::Paren(ParenExpr { expr: b })) => {
+            diff_at(&format!("{path}.expr"), a, b, out)
+        }
+        (
+            Expr::Binary(BinaryExpr {
+                op: op_a,
+                lhs: lhs_a,
+                rhs: rhs_a,
+                modifier: modifier_a,
+            }),
+            Expr::Binary(BinaryExpr {
+                op: op_b,
+                lhs: lhs_b,
+                rhs: rhs_b,
+                modifier: modifier_b,
+            }),
+        ) => {
+            if op_a != op_b {
+                push(out, path, changed("operator", op_a, op_b));
+            }
+            // Compare what is written: the parser fills in an implicit
+            // cardinality, e.g. for set operators, that doesn't show.
+            let modifier = |m: &Option<BinModifier>| match m.as_ref().map(ToString::to_string) {
+                Some(m) if !m.is_empty() => m.trim().to_string(),
+                _ => "none".to_string(),
+            };
+            let (modifier_a, modifier_b) = (modifier(modifier_a), modifier(modifier_b));
+            if modifier_a != modifier_b {
+                push(out, path, changed("modifier", modifier_a, modifier_b));
+            }
+            diff_at(&format!("{path}.lhs"), lhs_a, lhs_b, out);
+            diff_at(&format!("{path}.rhs"), rhs_a, rhs_b, out);
+        }
+        (Expr::Subquery(a), Expr::Subquery(b)) => {
+            let SubqueryExpr {
+                expr: expr_a,
+                offset: offset_a,
+                at: at_a,
+                range: range_a,
+                step: step_a,
+            } = a;
+            if range_a != &b.range {
+                push(
+                    out,
+                    path,
+                    changed(
+                        "range",
+                        display_duration(range_a),
+                        display_duration(&b.range),
+                    ),
+                );
+            }
+            if step_a != &b.step {
+                let step = |s: &Option<_>| s.as_ref().map_or("none".to_string(), display_duration);
+                push(out, path, changed("step", step(step_a), step(&b.step)));
+            }
+            if offset_a != &b.offset {
+                push(
+                    out,
+                    path,
+                    changed("offset", optional(offset_a), optional(&b.offset)),
+                );
+            }
+            if at_a != &b.at {
+                push(out, path, changed("@", at(at_a), at(&b.at)));
+            }
+            diff_at(&format!("{path}.expr"), expr_a, &b.expr, out);
+        }
+        (
+            Expr::NumberLiteral(NumberLiteral { val: a }),
+            Expr::NumberLiteral(NumberLiteral { val: b }),
+        ) => {
+            if a != b && !(a.is_nan() && b.is_nan()) {
+                push(out, path, changed("value", a, b));
+            }
+        }
+        (
+            Expr::StringLiteral(StringLiteral { val: a }),
+            Expr::StringLiteral(StringLiteral { val: b }),
+        ) => {
+            let (a, b) = (format::unescape(a), format::unescape(b));
+            if a != b {
+                push(
+                    out,
+                    path,
+                    changed("value", format::quote(&a), format::quote(&b)),
+                );
+            }
+        }
+        (Expr::VectorSelector(a), Expr::VectorSelector(b)) => diff_selector(path, a, b, out),
+        (
+            Expr::MatrixSelector(MatrixSelector {
+                vs: vs_a,
+                range: range_a,
+            }),
+            Expr::MatrixSelector(MatrixSelector {
+                vs: vs_b,
+                range: range_b,
+            }),
+        ) => {
+            if range_a != range_b {
+                push(
+                    out,
+                    path,
+                    changed(
+                        "range",
+                        display_duration(range_a),
+                        display_duration(range_b),
+                    ),
+                );
+            }
+            diff_selector(&format!("{path}.vector_selector"), vs_a, vs_b, out);
+        }
+        (
+            Expr::Call(Call {
+                func: func_a,
+                args: args_a,
+            }),
+            Expr::Call(Call {
+                func: func_b,
+                args: args_b,
+            }),
+        ) => {
+            if func_a.name != func_b.name {
+                push(out, path, changed("function", func_a.name, func_b.name));
+            }
+            if args_a.len() != args_b.len() {
+                push(
+                    out,
+                    path,
+                    changed("argument count", args_a.len(), args_b.len()),
+                );
+            } else {
+                for (i, (a, b)) in args_a.args.iter().zip(&args_b.args).enumerate() {
+                    diff_at(&format!("{path}.args[{i}]"), a, b, out);
+                }
+            }
+        }
+        (Expr::Extension(_), Expr::Extension(_)) => {
+            if a != b {
+                push(
+                    out,
+                    path,
+                    format!("`{}` replaced by `{}`", Promql(a), Promql(b)),
+                );
+            }
+        }
+        _ => push(
+            out,
+            path,
+            format!("`{}` replaced by `{}`", Promql(a), Promql(b)),
+        ),
+    }
+}
+
+fn diff_selector(path: &str, a: &VectorSelector, b: &VectorSelector, out: &mut Vec<String>) {
+    if a.name != b.name {
+        push(
+            out,
+            path,
+            changed("metric", optional(&a.name), optional(&b.name)),
+        );
+    }
+    if a.matchers.or_matchers.is_empty() && b.matchers.or_matchers.is_empty() {
+        for what in diff_matchers(&a.matchers, &b.matchers) {
+            push(out, path, what);
+        }
+    } else if format::matchers_to_string(&a.matchers) != format::matchers_to_string(&b.matchers) {
+        push(
+            out,
+            path,
+            changed(
+                "matchers",
+                format!("{{{}}}", format::matchers_to_string(&a.matchers)),
+                format!("{{{}}}", format::matchers_to_string(&b.matchers)),
+            ),
+        );
+    }
+    if a.offset != b.offset {
+        push(
+            out,
+            path,
+            changed("offset", optional(&a.offset), optional(&b.offset)),
+        );
+    }
+    if a.at != b.at {
+        push(out, path, changed("@", at(&a.at), at(&b.at)));
+    }
+}
+
+/// Describe how plain matchers changed. A label matched by exactly one
+/// matcher on each side is reported as changed, others as added or removed.
+fn diff_matchers(a: &Matchers, b: &Matchers) -> Vec<String> {
+    let strings = |m: &Matchers| -> BTreeSet<(String, String)> {
+        m.matchers
+            .iter()
+            .map(|m| (m.name.clone(), format::matcher_to_string(m)))
+            .collect()
+    };
+    let (a, b) = (strings(a), strings(b));
+    let removed: Vec<_> = a.difference(&b).collect();
+    let added: Vec<_> = b.difference(&a).collect();
+    let count =
+        |list: &[&(String, String)], name: &str| list.iter().filter(|(n, _)| n == name).count();
+    let mut changes = Vec::new();
+    for (name, old) in &removed {
+        if count(&removed, name) == 1 && count(&added, name) == 1 {
+            let (_, new) = added.iter().find(|(n, _)| n == name).unwrap();
+            changes.push(format!("matcher {name} changed from {old} to {new}"));
+        } else {
+            changes.push(format!("matcher {old} removed"));
+        }
+    }
+    for (name, new) in &added {
+        if !(count(&removed, name) == 1 && count(&added, name) == 1) {
+            changes.push(format!("matcher {new} added"));
+        }
+    }
+    changes
+}
+
+fn push(out: &mut Vec<String>, path: &str, change: String) {
+    out.push(format!("{path}: {change}"));
+}
+
+fn changed(what: &str, from: impl Display, to: impl Display) -> String {
+    format!("{what} changed from {from} to {to}")
+}
+
+fn optional(value: &Option<impl Display>) -> String {
+    value
+        .as_ref()
+        .map_or("none".to_string(), |value| value.to_string())
+}
+
+fn at(at: &Option<AtModifier>) -> String {
+    at.as_ref().map_or("none".to_string(), format::at_to_string)
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+
+use promql::parser::{
+    token, AggregateExpr, BinaryExpr, Call, Expr, LabelModifier, MatrixSelector, ParenExpr,
+    SubqueryExpr, UnaryExpr, VectorSelector,
+};
+use promql::util::display_duration;
+
+use crate::format::{self, Promql};
+
+/// Functions over a range vector described as "<range> <name> of <series>".
+const RATE_FUNCTIONS: &[&str] = &["rate", "irate", "increase", "delta", "idelta", "deriv"];
+
+/// Describes an [`Expr`] in plain English, with one fixed template per node
+/// type, e.g. `5m rate of http_requests_total, summed by job`.
+pub struct Explain<'a>(pub &'a Expr);
+
+impl fmt::Display for Explain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expr::Aggregate(AggregateExpr {
+                op,
+                expr,
+                param,
+                modifier,
+            }) => {
+                write!(f, "{}, ", Explain(expr))?;
+                let param = param.as_deref().map(Explain);
+                match (op.id(), param) {
+                    (token::T_SUM, _) => write!(f, "summed")?,
+                    (token::T_AVG, _) => write!(f, "averaged")?,
+                    (token::T_MIN, _) => write!(f, "minimum taken")?,
+                    (token::T_MAX, _) => write!(f, "maximum taken")?,
+                    (token::T_COUNT, _) => write!(f, "counted")?,
+                    (token::T_GROUP, _) => write!(f, "grouped")?,
+                    (token::T_STDDEV, _) => write!(f, "standard deviation taken")?,
+                    (token::T_STDVAR, _) => write!(f, "variance taken")?,
+                    (token::T_TOPK, Some(k)) => write!(f, "top {k} kept")?,
+                    (token::T_BOTTOMK, Some(k)) => write!(f, "bottom {k} kept")?,
+                    (token::T_QUANTILE, Some(q)) => write!(f, "{q} quantile taken")?,
+                    (token::T_COUNT_VALUES, Some(label)) => {
+                        write!(f, "values counted into label {label}")?
+                    }
+                    _ => write!(f, "aggregated with {op}")?,
+                }
+                match modifier {
+                    Some(LabelModifier::Include(ls)) if !ls.is_empty() => write!(f, " by {ls}"),
+                    Some(LabelModifier::Exclude(ls)) => write!(f, " without {ls}"),
+                    _ => Ok(()),
+                }
+            }
+            Expr::Unary(UnaryExpr { expr }) => write!(f, "negated {}", Explain(expr)),
+            Expr::Binary(BinaryExpr {
+                op,
+                lhs,
+                rhs,
+                modifier,
+            }) => {
+                let phrase = match op.id() {
+                    token::T_ADD => "plus",
+                    token::T_SUB => "minus",
+                    token::T_MUL => "times",
+                    token::T_DIV => "divided by",
+                    token::T_MOD => "modulo",
+                    token::T_POW => "to the power of",
+                    token::T_ATAN2 => "atan2 with",
+                    token::T_EQLC => "equal to",
+                    token::T_NEQ => "not equal to",
+                    token::T_GTR => "greater than",
+                    token::T_LSS => "less than",
+                    token::T_GTE => "greater than or equal to",
+                    token::T_LTE => "less than or equal to",
+                    token::T_LAND => "where also present in",
+                    token::T_LOR => "or else",
+                    token::T_LUNLESS => "unless present in",
+                    _ => "combined with",
+                };
+                let return_bool = modifier.as_ref().is_some_and(|m| m.return_bool);
+                if op.is_comparison_operator() && !return_bool {
+                    write!(f, "{} where {phrase} {}", Operand(lhs), Operand(rhs))
+                } else if op.is_comparison_operator() {
+                    write!(f, "whether {} is {phrase} {}", Operand(lhs), Operand(rhs))
+                } else {
+                    write!(f, "{} {phrase} {}", Operand(lhs), Operand(rhs))
+                }
+            }
+            Expr::Paren(ParenExpr { expr }) => write!(f, "{}", Explain(expr)),
+            Expr::Subquery(SubqueryExpr {
+                expr, range, step, ..
+            }) => {
+                write!(f, "{} ", Explain(expr))?;
+                match step {
+                    Some(step) => write!(f, "every {}", display_duration(step))?,
+                    None => write!(f, "at the default resolution")?,
+                }
+                write!(f, " over the last {}", display_duration(range))
+            }
+            Expr::NumberLiteral(_) | Expr::StringLiteral(_) => write!(f, "{}", Promql(self.0)),
+            Expr::VectorSelector(vs) => write_selector(f, vs),
+            Expr::MatrixSelector(MatrixSelector { vs, range }) => {
+                write!(f, "the last {} of ", display_duration(range))?;
+                write_selector(f, vs)
+            }
+            Expr::Call(Call { func, args }) => match args.args.first().map(|arg| arg.as_ref()) {
+                Some(Expr::MatrixSelector(MatrixSelector { vs, range }))
+                    if RATE_FUNCTIONS.contains(&func.name) =>
+                {
+                    write!(f, "{} {} of ", display_duration(range), func.name)?;
+                    write_selector(f, vs)
+                }
+                Some(Expr::MatrixSelector(MatrixSelector { vs, range }))
+                    if func.name.ends_with("_over_time") && args.args.len() == 1 =>
+                {
+                    let name = func.name.trim_end_matches("_over_time");
+                    write!(f, "{name} over {} of ", display_duration(range))?;
+                    write_selector(f, vs)
+                }
+                _ => {
+                    write!(f, "{} of ", func.name)?;
+                    for (i, arg) in args.args.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, " and ")?;
+                        }
+                        write!(f, "{}", Operand(arg))?;
+                    }
+                    Ok(())
+                }
+            },
+            Expr::Extension(ext) => write!(f, "{}", ext.expr.name()),
+        }
+    }
+}
+
+/// Explains an operand, in parentheses if it is itself a compound phrase.
+struct Operand<'a>(&'a Expr);
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expr::Binary(_) | Expr::Aggregate(_) | Expr::Subquery(_) => {
+                write!(f, "({})", Explain(self.0))
+            }
+            Expr::Paren(ParenExpr { expr }) => write!(f, "{}", Operand(expr)),
+            _ => write!(f, "{}", Explain(self.0)),
+        }
+    }
+}
+
+fn write_selector(f: &mut fmt::Formatter<'_>, vs: &VectorSelector) -> fmt::Result {
+    write!(f, "{}", vs.name.as_deref().unwrap_or("series"))?;
+    let matchers = format::matchers_to_string(&vs.matchers);
+    if !matchers.is_empty() {
+        write!(f, " with {matchers}")?;
+    }
+    if let Some(offset) = &vs.offset {
+        write!(f, " offset by {offset}")?;
+    }
+    if let Some(at) = &vs.at {
+        write!(f, " at {}", format::at_to_string(at))?;
+    }
+    Ok(())
+}
@@ -1,22 +1,103 @@
+use std::collections::BTreeSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::time::SystemTime;
 
-use chrono::Duration;
-use promql_parser::label::Label;
-use promql_parser::parser::{
-    self, token::TokenType, value::ValueType, AggregateExpr, AtModifier, BinaryExpr, Call, Expr,
-    LabelModifier, MatrixSelector, NumberLiteral, Offset, ParenExpr, StringLiteral, SubqueryExpr,
-    UnaryExpr, VectorMatchCardinality, VectorSelector,
+use chrono::{DateTime, Duration, FixedOffset};
+use promql::label::{MatchOp, Matchers, METRIC_NAME};
+use promql::parser::{
+    self, token, token::TokenType, value::ValueType, AggregateExpr, AtModifier, BinModifier,
+    BinaryExpr, Call, Expr, Extension, LabelModifier, MatrixSelector, NumberLiteral, Offset,
+    ParenExpr, StringLiteral, SubqueryExpr, UnaryExpr, VectorMatchCardinality, VectorSelector,
 };
-use pyo3::exceptions::{PyNotImplementedError, PyOverflowError, PyValueError};
+use pyo3::exceptions::{PyOverflowError, PyValueError};
+use pyo3::pybacked::{PyBackedBytes, PyBackedStr};
+use pyo3::types::{PyDateTime, PyFrozenSet, PySet, PyTzInfoAccess};
 use pyo3::{prelude::*, IntoPyObjectExt};
 
-#[pyclass(subclass, name = "Expr", module = "promql_parser")]
+use crate::desugar;
+use crate::explain::Explain;
+use crate::format::{self, Promql, Repr};
+use crate::walk;
+
+/// Query text as accepted by the `parse` functions: `str`, or UTF-8 encoded
+/// `bytes`/`bytearray`.
+#[derive(FromPyObject)]
+pub enum Query {
+    #[pyo3(annotation = "str")]
+    Str(PyBackedStr),
+    #[pyo3(annotation = "bytes")]
+    Bytes(PyBackedBytes),
+}
+
+impl Query {
+    pub fn as_str(&self) -> PyResult<&str> {
+        match self {
+            Query::Str(s) => Ok(s),
+            Query::Bytes(b) => std::str::from_utf8(b)
+                .map_err(|e| PyValueError::new_err(format!("query is not valid UTF-8: {e}"))),
+        }
+    }
+}
+
+/// Weight of a matrix selector in [`PyExpr::complexity`], since it reads a
+/// whole range of samples per series.
+const MATRIX_SELECTOR_WEIGHT: usize = 5;
+/// Weight of a subquery in [`PyExpr::complexity`], since it evaluates its
+/// inner expression at every step.
+const SUBQUERY_WEIGHT: usize = 10;
+
+/// Functions reported by [`PyExpr::deprecated_functions`]. The function table
+/// of `promql-parser` carries no such flag, so this list is kept by hand:
+/// `holt_winters` is deprecated in favour of `double_exponential_smoothing`,
+/// and the native histogram functions are experimental in Prometheus 2.45.
+const DEPRECATED_FUNCTIONS: &[&str] = &[
+    "holt_winters",
+    "histogram_count",
+    "histogram_sum",
+    "histogram_fraction",
+];
+
+/// Functions reported by [`PyExpr::uses_histogram_functions`], kept by hand
+/// since the function table doesn't group them either. These are all the
+/// histogram functions the parser knows, classic and native alike.
+const HISTOGRAM_FUNCTIONS: &[&str] = &[
+    "histogram_count",
+    "histogram_sum",
+    "histogram_fraction",
+    "histogram_quantile",
+];
+
+/// Base class of every AST node.
+///
+/// All AST classes are `frozen`: a node is fully built in `create` and never
+/// mutated afterwards, so it can be shared across threads on free-threaded
+/// Python builds without any runtime borrow checking.
+#[pyclass(subclass, frozen, name = "Expr", module = "promql_parser")]
 #[derive(Debug, Clone)]
 pub struct PyExpr {
     pub expr: Expr,
 }
 
 impl PyExpr {
+    /// Borrow the parsed tree, e.g. from a Rust crate that links this one as
+    /// a library and receives `Expr` objects from Python:
+    ///
+    /// ```ignore
+    /// let node = obj.downcast::<PyExpr>()?;
+    /// let expr: &promql::parser::Expr = node.get().as_expr();
+    /// ```
+    ///
+    /// Rust layouts are not ABI-stable, so this only works for objects
+    /// created by the same build of this crate, not by a separately compiled
+    /// `promql_parser` extension module.
+    ///
+    /// This crate and `promql-parser` both build a library named
+    /// `promql_parser`, so a crate depending on both must rename one of them
+    /// in `Cargo.toml`, as this crate does by depending on `promql`.
+    pub fn as_expr(&self) -> &Expr {
+        &self.expr
+    }
+
     pub fn create(py: Python, expr: Expr) -> PyResult<PyObject> {
         match expr {
             Expr::Aggregate(agg) => PyAggregateExpr::create(py, agg),
@@ -29,30 +110,618 @@ impl PyExpr {
             Expr::VectorSelector(selector) => PyVectorSelector::create(py, selector),
             Expr::MatrixSelector(selector) => PyMatrixSelector::create(py, selector),
             Expr::Call(call) => PyCall::create(py, call),
-            Expr::Extension(_ext) => Err(PyNotImplementedError::new_err("extension unimplemented")),
+            Expr::Extension(ext) => PyExtensionExpr::create(py, ext),
+        }
+    }
+
+    fn parse_expecting(py: Python, input: Query, expected: ValueType) -> PyResult<PyObject> {
+        let expr = parser::parse(input.as_str()?).map_err(PyValueError::new_err)?;
+        let actual = expr.value_type();
+        if actual != expected {
+            return Err(PyValueError::new_err(format!(
+                "expected {expected} expression, got {actual}"
+            )));
         }
+        Self::create(py, expr)
     }
 }
 
 #[pymethods]
 impl PyExpr {
     #[staticmethod]
-    pub fn parse(py: Python, input: &str) -> PyResult<PyObject> {
-        let expr = parser::parse(input).map_err(PyValueError::new_err)?;
+    #[pyo3(signature = (input, *, max_length=None, max_matchers=None, desugar=false))]
+    pub fn parse(
+        py: Python,
+        input: Query,
+        max_length: Option<usize>,
+        max_matchers: Option<usize>,
+        desugar: bool,
+    ) -> PyResult<PyObject> {
+        let input = input.as_str()?;
+        if let Some(max_length) = max_length {
+            let length = input.chars().count();
+            if length > max_length {
+                return Err(PyValueError::new_err(format!(
+                    "query is {length} characters long, exceeding max_length of {max_length}"
+                )));
+            }
+        }
+        let mut expr = parser::parse(input).map_err(PyValueError::new_err)?;
+        if let Some(max_matchers) = max_matchers {
+            let count = count_matchers(&expr);
+            if count > max_matchers {
+                return Err(PyValueError::new_err(format!(
+                    "query has {count} label matchers, exceeding max_matchers of {max_matchers}"
+                )));
+            }
+        }
+        if desugar {
+            desugar::desugar(&mut expr);
+        }
         let py_expr = Self::create(py, expr)?;
         Ok(py_expr)
     }
 
+    #[staticmethod]
+    fn parse_vector(py: Python, input: Query) -> PyResult<PyObject> {
+        Self::parse_expecting(py, input, ValueType::Vector)
+    }
+
+    #[staticmethod]
+    fn parse_scalar(py: Python, input: Query) -> PyResult<PyObject> {
+        Self::parse_expecting(py, input, ValueType::Scalar)
+    }
+
+    fn kind(&self) -> &'static str {
+        // The class name without its `Expr` suffix, e.g. `Aggregate`.
+        let name = class_name(&self.expr);
+        name.strip_suffix("Expr").unwrap_or(name)
+    }
+
+    fn value_type(&self) -> PyValueType {
+        self.expr.value_type().into()
+    }
+
+    fn is_instant_vector(&self) -> bool {
+        self.expr.value_type() == ValueType::Vector
+    }
+
+    fn is_range_vector(&self) -> bool {
+        self.expr.value_type() == ValueType::Matrix
+    }
+
+    fn is_scalar(&self) -> bool {
+        self.expr.value_type() == ValueType::Scalar
+    }
+
+    fn is_string(&self) -> bool {
+        self.expr.value_type() == ValueType::String
+    }
+
+    fn explain(&self) -> String {
+        Explain(&self.expr).to_string()
+    }
+
     fn prettify(&self) -> String {
         self.expr.prettify()
     }
 
+    fn canonical(&self) -> String {
+        format::canonical(&self.expr)
+    }
+
+    fn without_time_modifiers(&self, py: Python) -> PyResult<PyObject> {
+        let mut expr = self.expr.clone();
+        walk::visit_mut(&mut expr, &mut |expr| match expr {
+            Expr::VectorSelector(vs) => {
+                vs.at = None;
+                vs.offset = None;
+            }
+            Expr::MatrixSelector(ms) => {
+                ms.vs.at = None;
+                ms.vs.offset = None;
+            }
+            Expr::Subquery(sq) => {
+                sq.at = None;
+                sq.offset = None;
+            }
+            _ => {}
+        });
+        Self::create(py, expr)
+    }
+
+    fn with_at(&self, py: Python, timestamp: &Bound<'_, PyDateTime>) -> PyResult<PyObject> {
+        let at = AtModifier::At(system_time(timestamp)?);
+        let mut expr = self.expr.clone();
+        set_outer_at(&mut expr, &at);
+        Self::create(py, expr)
+    }
+
+    fn deprecated_functions(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        walk::any(&self.expr, &mut |expr| {
+            if let Expr::Call(call) = expr {
+                if DEPRECATED_FUNCTIONS.contains(&call.func.name)
+                    && !names.contains(&call.func.name)
+                {
+                    names.push(call.func.name);
+                }
+            }
+            false
+        });
+        names
+    }
+
+    fn uses_histogram_functions(&self) -> bool {
+        walk::any(
+            &self.expr,
+            &mut |expr| matches!(expr, Expr::Call(call) if HISTOGRAM_FUNCTIONS.contains(&call.func.name)),
+        )
+    }
+
+    fn label_names_used(&self) -> Vec<&str> {
+        let mut names = BTreeSet::new();
+        walk::any(&self.expr, &mut |expr| {
+            match expr {
+                Expr::VectorSelector(VectorSelector { name, matchers, .. })
+                | Expr::MatrixSelector(MatrixSelector {
+                    vs: VectorSelector { name, matchers, .. },
+                    ..
+                }) => {
+                    // `foo` is shorthand for `{__name__="foo"}`.
+                    if name.is_some() {
+                        names.insert(METRIC_NAME);
+                    }
+                    let or_matchers = matchers.or_matchers.iter().flatten();
+                    names.extend(
+                        matchers
+                            .matchers
+                            .iter()
+                            .chain(or_matchers)
+                            .map(|m| m.name.as_str()),
+                    );
+                }
+                Expr::Aggregate(AggregateExpr {
+                    modifier: Some(modifier),
+                    ..
+                }) => names.extend(modifier.labels().labels.iter().map(String::as_str)),
+                Expr::Binary(BinaryExpr {
+                    modifier: Some(modifier),
+                    ..
+                }) => {
+                    if let Some(matching) = &modifier.matching {
+                        names.extend(matching.labels().labels.iter().map(String::as_str));
+                    }
+                    if let Some(labels) = modifier.card.labels() {
+                        names.extend(labels.labels.iter().map(String::as_str));
+                    }
+                }
+                _ => {}
+            }
+            false
+        });
+        names.into_iter().collect()
+    }
+
+    fn is_simple_selector(&self) -> bool {
+        matches!(self.expr, Expr::VectorSelector(_))
+    }
+
+    fn drops_metric_name(&self) -> bool {
+        drops_metric_name(&self.expr)
+    }
+
+    fn find_all(&self, py: Python, node_type: &str) -> PyResult<Vec<PyObject>> {
+        let pattern = node_type.as_bytes();
+        if !NODE_TYPES
+            .iter()
+            .any(|name| glob_match(pattern, name.as_bytes()))
+        {
+            return Err(PyValueError::new_err(format!(
+                "unknown node type '{node_type}', expected one of: {}",
+                NODE_TYPES.join(", ")
+            )));
+        }
+        let mut found = Vec::new();
+        walk::any(&self.expr, &mut |expr| {
+            if glob_match(pattern, class_name(expr).as_bytes()) {
+                found.push(expr.clone());
+            }
+            // The selector of a range selector is exposed as a node in Python too.
+            if let Expr::MatrixSelector(ms) = expr {
+                if glob_match(pattern, b"VectorSelector") {
+                    found.push(Expr::VectorSelector(ms.vs.clone()));
+                }
+            }
+            false
+        });
+        found
+            .into_iter()
+            .map(|expr| Self::create(py, expr))
+            .collect()
+    }
+
+    fn any(slf: &Bound<'_, Self>, predicate: &Bound<'_, PyAny>) -> PyResult<bool> {
+        Ok(Self::find_first(slf, predicate)?.is_some())
+    }
+
+    fn find_first(
+        slf: &Bound<'_, Self>,
+        predicate: &Bound<'_, PyAny>,
+    ) -> PyResult<Option<PyObject>> {
+        let mut stack = vec![slf.clone().into_any()];
+        while let Some(node) = stack.pop() {
+            if predicate.call1((&node,))?.is_truthy()? {
+                return Ok(Some(node.unbind()));
+            }
+            stack.extend(py_children(&node).into_iter().rev());
+        }
+        Ok(None)
+    }
+
+    fn vector_selectors(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        let mut found = Vec::new();
+        walk::any(&self.expr, &mut |expr| {
+            match expr {
+                Expr::VectorSelector(vs) => found.push(vs.clone()),
+                Expr::MatrixSelector(ms) => found.push(ms.vs.clone()),
+                _ => {}
+            }
+            false
+        });
+        found
+            .into_iter()
+            .map(|vs| Self::create(py, Expr::VectorSelector(vs)))
+            .collect()
+    }
+
+    fn selectors(&self) -> Vec<(Option<String>, PyMatchers)> {
+        let mut selectors = Vec::new();
+        walk::any(&self.expr, &mut |expr| {
+            let vs = match expr {
+                Expr::VectorSelector(vs) => vs,
+                Expr::MatrixSelector(ms) => &ms.vs,
+                _ => return false,
+            };
+            selectors.push((metric_name(vs), vs.matchers.clone().into()));
+            false
+        });
+        selectors
+    }
+
+    fn rename_metric(&self, py: Python, old: &str, new: &str) -> PyResult<PyObject> {
+        if !is_metric_name(new) {
+            return Err(PyValueError::new_err(format!(
+                "'{new}' is not a valid metric name"
+            )));
+        }
+        let mut expr = self.expr.clone();
+        walk::visit_mut(&mut expr, &mut |expr| {
+            let vs = match expr {
+                Expr::VectorSelector(vs) => vs,
+                Expr::MatrixSelector(ms) => &mut ms.vs,
+                _ => return,
+            };
+            if metric_name(vs).as_deref() != Some(old) {
+                return;
+            }
+            if vs.name.is_some() {
+                vs.name = Some(new.to_string());
+            }
+            for m in &mut vs.matchers.matchers {
+                if m.name == METRIC_NAME && m.op == MatchOp::Equal {
+                    m.value = new.to_string();
+                }
+            }
+        });
+        Self::create(py, expr)
+    }
+
+    fn node_count(&self) -> usize {
+        let mut count = 0;
+        walk::any(&self.expr, &mut |_| {
+            count += 1;
+            false
+        });
+        count
+    }
+
+    fn complexity(&self) -> usize {
+        let mut complexity = 0;
+        walk::any(&self.expr, &mut |expr| {
+            complexity += match expr {
+                Expr::MatrixSelector(_) => MATRIX_SELECTOR_WEIGHT,
+                Expr::Subquery(_) => SUBQUERY_WEIGHT,
+                _ => 1,
+            };
+            false
+        });
+        complexity
+    }
+
+    fn contains_at_modifier(&self) -> bool {
+        walk::any(&self.expr, &mut |expr| match expr {
+            Expr::VectorSelector(vs) => vs.at.is_some(),
+            Expr::MatrixSelector(ms) => ms.vs.at.is_some(),
+            Expr::Subquery(sq) => sq.at.is_some(),
+            _ => false,
+        })
+    }
+
+    fn contains_offset(&self) -> bool {
+        walk::any(&self.expr, &mut |expr| match expr {
+            Expr::VectorSelector(vs) => vs.offset.is_some(),
+            Expr::MatrixSelector(ms) => ms.vs.offset.is_some(),
+            Expr::Subquery(sq) => sq.offset.is_some(),
+            _ => false,
+        })
+    }
+
+    fn __str__(&self) -> String {
+        Promql(&self.expr).to_string()
+    }
+
+    fn __eq__(&self, other: &Bound<'_, PyExpr>) -> bool {
+        self.expr == other.get().expr
+    }
+
+    fn __hash__(&self) -> u64 {
+        // Structurally equal trees render the same, once `-0` and `0`, which
+        // compare equal, are rendered alike.
+        let mut expr = self.expr.clone();
+        walk::visit_mut(&mut expr, &mut |expr| {
+            if let Expr::NumberLiteral(lit) = expr {
+                if lit.val == 0.0 {
+                    lit.val = 0.0;
+                }
+            }
+        });
+        let mut hasher = DefaultHasher::new();
+        Promql(&expr).to_string().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn equals_query(&self, text: Query) -> PyResult<bool> {
+        Ok(parser::parse(text.as_str()?).is_ok_and(|expr| expr == self.expr))
+    }
+
     fn __repr__(&self) -> String {
+        Repr(&self.expr).to_string()
+    }
+
+    fn debug_repr(&self) -> String {
         format!("{:#?}", self.expr)
     }
 }
 
-#[pyclass(extends = PyExpr, name = "AggregateExpr", module = "promql_parser")]
+/// Define `NODE_TYPES` and `class_name` from one table, so that the list
+/// `find_all` checks against can't drift from the classes actually built.
+macro_rules! node_types {
+    ($($variant:ident => $class:literal,)*) => {
+        /// Python class names of the AST node types, as matched by `find_all`.
+        const NODE_TYPES: &[&str] = &[$($class),*];
+
+        /// The Python class name of the node `PyExpr::create` builds for `expr`.
+        fn class_name(expr: &Expr) -> &'static str {
+            match expr {
+                $(Expr::$variant(_) => $class,)*
+            }
+        }
+    };
+}
+
+node_types! {
+    Aggregate => "AggregateExpr",
+    Unary => "UnaryExpr",
+    Binary => "BinaryExpr",
+    Paren => "ParenExpr",
+    Subquery => "SubqueryExpr",
+    NumberLiteral => "NumberLiteral",
+    StringLiteral => "StringLiteral",
+    VectorSelector => "VectorSelector",
+    MatrixSelector => "MatrixSelector",
+    Call => "Call",
+    Extension => "ExtensionExpr",
+}
+
+/// Match `name` against a shell-style `pattern` supporting `*` and `?`.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name))) => glob_match(rest, name),
+        (Some((p, rest)), Some((n, name))) => p == n && glob_match(rest, name),
+        _ => false,
+    }
+}
+
+/// Count the label matchers of all selectors in `expr`, including those in
+/// `or` groups.
+fn count_matchers(expr: &Expr) -> usize {
+    let mut count = 0;
+    walk::any(expr, &mut |expr| {
+        let matchers = match expr {
+            Expr::VectorSelector(vs) => &vs.matchers,
+            Expr::MatrixSelector(ms) => &ms.vs.matchers,
+            _ => return false,
+        };
+        count += matchers.matchers.len();
+        count += matchers.or_matchers.iter().map(Vec::len).sum::<usize>();
+        false
+    });
+    count
+}
+
+/// Functions whose result keeps the labels, including the metric name, of
+/// the series passed as their first argument.
+const NAME_PRESERVING_FUNCTIONS: &[&str] = &[
+    "last_over_time",
+    "label_join",
+    "label_replace",
+    "sort",
+    "sort_desc",
+];
+
+/// Whether the series resulting from `expr` lack a metric name, following
+/// the rules Prometheus applies when evaluating it.
+fn drops_metric_name(expr: &Expr) -> bool {
+    match expr {
+        Expr::VectorSelector(_) | Expr::MatrixSelector(_) => false,
+        Expr::Paren(ParenExpr { expr }) | Expr::Subquery(SubqueryExpr { expr, .. }) => {
+            drops_metric_name(expr)
+        }
+        Expr::Aggregate(AggregateExpr {
+            op, expr, modifier, ..
+        }) => match modifier {
+            _ if matches!(op.id(), token::T_TOPK | token::T_BOTTOMK) => drops_metric_name(expr),
+            Some(LabelModifier::Include(labels))
+                if labels.labels.iter().any(|l| l == METRIC_NAME) =>
+            {
+                drops_metric_name(expr)
+            }
+            _ => true,
+        },
+        Expr::Binary(BinaryExpr {
+            op,
+            lhs,
+            rhs,
+            modifier,
+        }) => {
+            let return_bool = modifier.as_ref().is_some_and(|m| m.return_bool);
+            match op.id() {
+                token::T_LAND | token::T_LUNLESS => drops_metric_name(lhs),
+                token::T_LOR => drops_metric_name(lhs) || drops_metric_name(rhs),
+                _ if op.is_comparison_operator() && !return_bool => {
+                    if lhs.value_type() == ValueType::Vector {
+                        drops_metric_name(lhs)
+                    } else {
+                        drops_metric_name(rhs)
+                    }
+                }
+                _ => true,
+            }
+        }
+        Expr::Call(Call { func, args }) if NAME_PRESERVING_FUNCTIONS.contains(&func.name) => {
+            match args.args.first() {
+                Some(arg) => drops_metric_name(arg),
+                None => true,
+            }
+        }
+        Expr::Call(_)
+        | Expr::Unary(_)
+        | Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::Extension(_) => true,
+    }
+}
+
+/// The metric `vs` selects, whether written as `foo` or `{__name__="foo"}`.
+fn metric_name(vs: &VectorSelector) -> Option<String> {
+    if let Some(name) = &vs.name {
+        return Some(name.clone());
+    }
+    vs.matchers
+        .matchers
+        .iter()
+        .find(|m| m.name == METRIC_NAME && m.op == MatchOp::Equal)
+        .map(|m| format::unescape(&m.value))
+}
+
+/// The child nodes of `node` as exposed in Python, in source order.
+///
+/// Unlike [`walk::children`], this returns the objects already built for the
+/// tree, and includes the `vector_selector` of a range selector.
+fn py_children<'py>(node: &Bound<'py, PyAny>) -> Vec<Bound<'py, PyAny>> {
+    let py = node.py();
+    let children: Vec<&PyObject> = if let Ok(node) = node.downcast::<PyAggregateExpr>() {
+        let node = node.get();
+        node.param.iter().chain([&node.expr]).collect()
+    } else if let Ok(node) = node.downcast::<PyUnaryExpr>() {
+        vec![&node.get().expr]
+    } else if let Ok(node) = node.downcast::<PyBinaryExpr>() {
+        vec![&node.get().lhs, &node.get().rhs]
+    } else if let Ok(node) = node.downcast::<PyParenExpr>() {
+        vec![&node.get().expr]
+    } else if let Ok(node) = node.downcast::<PySubqueryExpr>() {
+        vec![&node.get().expr]
+    } else if let Ok(node) = node.downcast::<PyCall>() {
+        node.get().args.iter().collect()
+    } else if let Ok(node) = node.downcast::<PyExtensionExpr>() {
+        node.get().children.iter().collect()
+    } else if let Ok(node) = node.downcast::<PyMatrixSelector>() {
+        return vec![node.get().vector_selector.bind(py).clone().into_any()];
+    } else {
+        Vec::new()
+    };
+    children
+        .into_iter()
+        .map(|child| child.bind(py).clone())
+        .collect()
+}
+
+/// Convert a parsed duration for Python, saturating at `timedelta.max`.
+///
+/// The parser accepts ranges far beyond what `timedelta` can hold, and those
+/// shouldn't make the rest of the tree uninspectable.
+fn timedelta(duration: std::time::Duration) -> Duration {
+    // `timedelta.max` is 999999999 days, 23:59:59.999999.
+    let max = Duration::days(999_999_999) + Duration::microseconds(86_400_000_000 - 1);
+    Duration::from_std(duration).map_or(max, |duration| duration.min(max))
+}
+
+/// Convert a parsed offset for Python, saturating at `timedelta.max` and
+/// `timedelta.min`.
+fn offset_timedelta(offset: Offset) -> Duration {
+    match offset {
+        Offset::Pos(off) => timedelta(off),
+        // `timedelta.min` is -999999999 days, just below `-timedelta.max`.
+        Offset::Neg(off) => -timedelta(off).min(Duration::days(999_999_999)),
+    }
+}
+
+/// Whether `name` matches `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Convert a timezone-aware `datetime`, rejecting naive ones rather than
+/// guessing their timezone, and ones before the Unix epoch, which the `at`
+/// getter cannot convert back.
+fn system_time(timestamp: &Bound<'_, PyDateTime>) -> PyResult<SystemTime> {
+    if timestamp.get_tzinfo().is_none() {
+        return Err(PyValueError::new_err("timestamp must be timezone-aware"));
+    }
+    let timestamp: DateTime<FixedOffset> = timestamp.extract()?;
+    if timestamp.timestamp() < 0 {
+        return Err(PyValueError::new_err(
+            "timestamp must not be before the Unix epoch",
+        ));
+    }
+    Ok(timestamp.into())
+}
+
+/// Set `at` on every selector and subquery of `expr` that is not nested in a
+/// subquery.
+fn set_outer_at(expr: &mut Expr, at: &AtModifier) {
+    match expr {
+        Expr::VectorSelector(vs) => vs.at = Some(at.clone()),
+        Expr::MatrixSelector(ms) => ms.vs.at = Some(at.clone()),
+        Expr::Subquery(sq) => sq.at = Some(at.clone()),
+        _ => {
+            for child in walk::children_mut(expr) {
+                set_outer_at(child, at);
+            }
+        }
+    }
+}
+
+#[pyclass(extends = PyExpr, frozen, name = "AggregateExpr", module = "promql_parser")]
 pub struct PyAggregateExpr {
     #[pyo3(get)]
     op: PyTokenType,
@@ -64,6 +733,14 @@ pub struct PyAggregateExpr {
     modifier: Option<PyAggModifier>,
 }
 
+#[pymethods]
+impl PyAggregateExpr {
+    #[getter]
+    fn op_name(&self) -> PyResult<&'static str> {
+        op_name(self.op.r#type)
+    }
+}
+
 impl PyAggregateExpr {
     fn create(py: Python, expr: AggregateExpr) -> PyResult<PyObject> {
         let parent = PyExpr {
@@ -98,7 +775,7 @@ impl PyAggregateExpr {
     }
 }
 
-#[pyclass(name = "TokenType", module = "promql_parser")]
+#[pyclass(frozen, name = "TokenType", module = "promql_parser")]
 #[derive(Debug, Clone, Copy)]
 pub struct PyTokenType {
     r#type: TokenType,
@@ -117,23 +794,61 @@ impl PyTokenType {
     }
 }
 
-#[pyclass(name = "AggModifier", module = "promql_parser")]
+/// A lexical token of a query, as produced by `tokenize`.
+#[pyclass(frozen, name = "Token", module = "promql_parser")]
+#[derive(Debug, Clone)]
+pub struct PyToken {
+    #[pyo3(get)]
+    kind: PyTokenType,
+    #[pyo3(get)]
+    text: String,
+    #[pyo3(get)]
+    start: usize,
+    #[pyo3(get)]
+    end: usize,
+}
+
+impl PyToken {
+    pub fn new(kind: TokenType, text: &str, start: usize, end: usize) -> Self {
+        PyToken {
+            kind: kind.into(),
+            text: text.to_string(),
+            start,
+            end,
+        }
+    }
+}
+
+#[pymethods]
+impl PyToken {
+    fn __repr__(&self) -> String {
+        format!(
+            "Token({}, {}, {}..{})",
+            self.kind.r#type,
+            format::quote(&self.text),
+            self.start,
+            self.end
+        )
+    }
+}
+
+#[pyclass(frozen, name = "AggModifier", module = "promql_parser")]
 #[derive(Debug, Clone)]
 pub struct PyAggModifier {
     #[pyo3(get)]
     r#type: PyAggModifierType,
     #[pyo3(get)]
-    labels: Vec<Label>,
+    labels: Vec<String>,
 }
 
-#[pyclass(name = "AggModifierType", module = "promql_parser", eq, eq_int)]
+#[pyclass(frozen, name = "AggModifierType", module = "promql_parser", eq, eq_int)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PyAggModifierType {
     By,
     Without,
 }
 
-#[pyclass(extends = PyExpr, name = "UnaryExpr", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "UnaryExpr", module = "promql_parser")]
 pub struct PyUnaryExpr {
     #[pyo3(get)]
     expr: PyObject,
@@ -152,7 +867,7 @@ impl PyUnaryExpr {
     }
 }
 
-#[pyclass(extends = PyExpr, name = "BinaryExpr", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "BinaryExpr", module = "promql_parser")]
 pub struct PyBinaryExpr {
     #[pyo3(get)]
     op: PyTokenType,
@@ -164,8 +879,55 @@ pub struct PyBinaryExpr {
     modifier: Option<PyBinModifier>,
 }
 
+#[pymethods]
+impl PyBinaryExpr {
+    #[new]
+    #[pyo3(signature = (op, lhs, rhs, modifier=None, *, return_bool=false))]
+    fn new(
+        py: Python,
+        op: &str,
+        lhs: &Bound<'_, PyExpr>,
+        rhs: &Bound<'_, PyExpr>,
+        modifier: Option<&Bound<'_, PyBinModifier>>,
+        return_bool: bool,
+    ) -> PyResult<PyClassInitializer<Self>> {
+        let op = binary_op(op)
+            .ok_or_else(|| PyValueError::new_err(format!("unknown binary operator '{op}'")))?;
+        let mut modifier = modifier.map(|modifier| modifier.get().inner.clone());
+        if return_bool {
+            modifier
+                .get_or_insert_with(BinModifier::default)
+                .return_bool = true;
+        }
+        let expr = Expr::Binary(BinaryExpr {
+            op,
+            lhs: operand(lhs, true),
+            rhs: operand(rhs, false),
+            modifier,
+        });
+        // Round-trip through the parser so the expression is validated exactly
+        // like parsed queries and normalized the same way.
+        let expr = parser::parse(&Promql(&expr).to_string()).map_err(PyValueError::new_err)?;
+        let Expr::Binary(expr) = expr else {
+            return Err(PyValueError::new_err(
+                "operands don't form a binary expression when rendered",
+            ));
+        };
+        Self::initializer(py, expr)
+    }
+
+    #[getter]
+    fn op_name(&self) -> PyResult<&'static str> {
+        op_name(self.op.r#type)
+    }
+}
+
 impl PyBinaryExpr {
     fn create(py: Python, expr: BinaryExpr) -> PyResult<PyObject> {
+        Py::new(py, Self::initializer(py, expr)?)?.into_py_any(py)
+    }
+
+    fn initializer(py: Python, expr: BinaryExpr) -> PyResult<PyClassInitializer<Self>> {
         let parent = PyExpr {
             expr: Expr::Binary(expr.clone()),
         };
@@ -177,6 +939,7 @@ impl PyBinaryExpr {
         } = expr;
         let py_modifier = match modifier {
             Some(modifier) => Some(PyBinModifier {
+                inner: modifier.clone(),
                 card: modifier.card.into(),
                 matching: match modifier.matching {
                     Some(LabelModifier::Include(labels)) => Some(PyLabelModifier {
@@ -199,11 +962,59 @@ impl PyBinaryExpr {
             rhs: PyExpr::create(py, *rhs)?,
             modifier: py_modifier,
         });
-        Py::new(py, initializer)?.into_py_any(py)
+        Ok(initializer)
     }
 }
 
-#[pyclass(name = "BinModifier", module = "promql_parser")]
+/// Map an operator as written in PromQL to its token.
+fn binary_op(op: &str) -> Option<TokenType> {
+    let id = match op {
+        "+" => token::T_ADD,
+        "-" => token::T_SUB,
+        "*" => token::T_MUL,
+        "/" => token::T_DIV,
+        "%" => token::T_MOD,
+        "^" => token::T_POW,
+        "atan2" => token::T_ATAN2,
+        "==" => token::T_EQLC,
+        "!=" => token::T_NEQ,
+        ">" => token::T_GTR,
+        "<" => token::T_LSS,
+        ">=" => token::T_GTE,
+        "<=" => token::T_LTE,
+        "and" => token::T_LAND,
+        "or" => token::T_LOR,
+        "unless" => token::T_LUNLESS,
+        _ => return None,
+    };
+    Some(TokenType::new(id))
+}
+
+/// The name of an operator or aggregation as written in PromQL.
+fn op_name(op: TokenType) -> PyResult<&'static str> {
+    format::op_name(op).ok_or_else(|| PyValueError::new_err(format!("unknown operator '{op}'")))
+}
+
+/// Parenthesize an operand that would otherwise bind differently once
+/// rendered next to another operator.
+fn operand(expr: &Bound<'_, PyExpr>, is_lhs: bool) -> Box<Expr> {
+    let expr = &expr.get().expr;
+    match expr {
+        Expr::Binary(_) => Box::new(Expr::Paren(ParenExpr {
+            expr: Box::new(expr.clone()),
+        })),
+        // `-1 ^ 2` parses as `-(1 ^ 2)`, so a negative literal on the left
+        // needs parentheses just like a unary expression.
+        Expr::Unary(_) | Expr::NumberLiteral(_) if is_lhs && !desugar::is_atom(expr) => {
+            Box::new(Expr::Paren(ParenExpr {
+                expr: Box::new(expr.clone()),
+            }))
+        }
+        _ => Box::new(expr.clone()),
+    }
+}
+
+#[pyclass(frozen, name = "BinModifier", module = "promql_parser")]
 #[derive(Debug, Clone)]
 pub struct PyBinModifier {
     #[pyo3(get)]
@@ -212,25 +1023,59 @@ pub struct PyBinModifier {
     matching: Option<PyLabelModifier>,
     #[pyo3(get)]
     return_bool: bool,
+    inner: BinModifier,
 }
 
-#[pyclass(name = "LabelModifier", module = "promql_parser")]
+#[pymethods]
+impl PyBinModifier {
+    fn on_labels(&self) -> Vec<String> {
+        match &self.matching {
+            Some(matching) if matching.r#type == PyLabelModifierType::Include => {
+                matching.labels.clone()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn ignoring_labels(&self) -> Vec<String> {
+        match &self.matching {
+            Some(matching) if matching.r#type == PyLabelModifierType::Exclude => {
+                matching.labels.clone()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[pyclass(frozen, name = "LabelModifier", module = "promql_parser")]
 #[derive(Debug, Clone)]
 pub struct PyLabelModifier {
     #[pyo3(get)]
     r#type: PyLabelModifierType,
     #[pyo3(get)]
-    labels: Vec<Label>,
+    labels: Vec<String>,
 }
 
-#[pyclass(name = "LabelModifierType", module = "promql_parser", eq, eq_int)]
+#[pyclass(
+    frozen,
+    name = "LabelModifierType",
+    module = "promql_parser",
+    eq,
+    eq_int
+)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PyLabelModifierType {
     Include,
     Exclude,
 }
 
-#[pyclass(name = "VectorMatchCardinality", module = "promql_parser", eq, eq_int)]
+#[pyclass(
+    frozen,
+    name = "VectorMatchCardinality",
+    module = "promql_parser",
+    eq,
+    eq_int
+)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PyVectorMatchCardinality {
     OneToOne,
@@ -250,7 +1095,7 @@ impl From<VectorMatchCardinality> for PyVectorMatchCardinality {
     }
 }
 
-#[pyclass(extends = PyExpr, name = "ParenExpr", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "ParenExpr", module = "promql_parser")]
 pub struct PyParenExpr {
     #[pyo3(get)]
     expr: PyObject,
@@ -269,7 +1114,7 @@ impl PyParenExpr {
     }
 }
 
-#[pyclass(extends = PyExpr, name = "SubqueryExpr", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "SubqueryExpr", module = "promql_parser")]
 pub struct PySubqueryExpr {
     #[pyo3(get)]
     expr: PyObject,
@@ -284,7 +1129,9 @@ pub struct PySubqueryExpr {
 }
 
 impl PySubqueryExpr {
-    fn create(py: Python, expr: SubqueryExpr) -> PyResult<PyObject> {
+    fn create(py: Python, mut expr: SubqueryExpr) -> PyResult<PyObject> {
+        // The parser rejects `offset 0s`, but trees built otherwise may have it.
+        desugar::strip_zero_offset(&mut expr.offset);
         let parent = PyExpr {
             expr: Expr::Subquery(expr.clone()),
         };
@@ -297,32 +1144,26 @@ impl PySubqueryExpr {
         } = expr;
         let initializer = PyClassInitializer::from(parent).add_subclass(PySubqueryExpr {
             expr: PyExpr::create(py, *expr)?,
-            offset: match offset {
-                Some(Offset::Pos(off)) => Some(
-                    Duration::from_std(off).map_err(|e| PyOverflowError::new_err(e.to_string()))?,
-                ),
-                Some(Offset::Neg(off)) => Some(
-                    -Duration::from_std(off)
-                        .map_err(|e| PyOverflowError::new_err(e.to_string()))?,
-                ),
-                None => None,
-            },
+            offset: offset.map(offset_timedelta),
             at: at.map(|at| at.into()),
-            range: Duration::from_std(range)
-                .map_err(|e| PyOverflowError::new_err(e.to_string()))?,
-            step: match step {
-                Some(step) => Some(
-                    Duration::from_std(step)
-                        .map_err(|e| PyOverflowError::new_err(e.to_string()))?,
-                ),
-                None => None,
-            },
+            range: timedelta(range),
+            step: step.map(timedelta),
         });
         Py::new(py, initializer)?.into_py_any(py)
     }
 }
 
-#[pyclass(name = "AtModifier", module = "promql_parser")]
+#[pymethods]
+impl PySubqueryExpr {
+    fn effective_step(&self, default: Duration) -> PyResult<Duration> {
+        if default <= Duration::zero() {
+            return Err(PyValueError::new_err("default step must be positive"));
+        }
+        Ok(self.step.unwrap_or(default))
+    }
+}
+
+#[pyclass(frozen, name = "AtModifier", module = "promql_parser")]
 #[derive(Debug, Clone)]
 pub struct PyAtModifier {
     #[pyo3(get)]
@@ -331,6 +1172,28 @@ pub struct PyAtModifier {
     at: Option<SystemTime>,
 }
 
+#[pymethods]
+impl PyAtModifier {
+    #[staticmethod]
+    fn from_datetime(timestamp: &Bound<'_, PyDateTime>) -> PyResult<Self> {
+        Ok(AtModifier::At(system_time(timestamp)?).into())
+    }
+
+    #[staticmethod]
+    fn start() -> Self {
+        AtModifier::Start.into()
+    }
+
+    #[staticmethod]
+    fn end() -> Self {
+        AtModifier::End.into()
+    }
+
+    fn timestamp(&self) -> Option<f64> {
+        self.at.as_ref().map(format::epoch_seconds)
+    }
+}
+
 impl From<AtModifier> for PyAtModifier {
     fn from(at: AtModifier) -> Self {
         let (typ, at) = match at {
@@ -342,7 +1205,20 @@ impl From<AtModifier> for PyAtModifier {
     }
 }
 
-#[pyclass(name = "AtModifierType", module = "promql_parser", eq, eq_int)]
+impl From<PyAtModifier> for AtModifier {
+    fn from(at: PyAtModifier) -> Self {
+        match (at.r#type, at.at) {
+            (PyAtModifierType::Start, _) => AtModifier::Start,
+            (PyAtModifierType::End, _) => AtModifier::End,
+            (PyAtModifierType::At, Some(at)) => AtModifier::At(at),
+            (PyAtModifierType::At, None) => {
+                unreachable!("`at` is always set for AtModifierType.At")
+            }
+        }
+    }
+}
+
+#[pyclass(frozen, name = "AtModifierType", module = "promql_parser", eq, eq_int)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PyAtModifierType {
     Start,
@@ -350,7 +1226,7 @@ pub enum PyAtModifierType {
     At,
 }
 
-#[pyclass(extends = PyExpr, name = "NumberLiteral", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "NumberLiteral", module = "promql_parser")]
 pub struct PyNumberLiteral {
     #[pyo3(get)]
     val: f64,
@@ -367,7 +1243,7 @@ impl PyNumberLiteral {
     }
 }
 
-#[pyclass(extends = PyExpr, name = "StringLiteral", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "StringLiteral", module = "promql_parser")]
 pub struct PyStringLiteral {
     #[pyo3(get)]
     val: String,
@@ -384,8 +1260,16 @@ impl PyStringLiteral {
     }
 }
 
-#[pyclass(name = "MatchOp", module = "promql_parser", eq, eq_int)]
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+#[pymethods]
+impl PyStringLiteral {
+    #[getter]
+    fn unescaped_val(&self) -> String {
+        format::unescape(&self.val)
+    }
+}
+
+#[pyclass(frozen, name = "MatchOp", module = "promql_parser", eq, eq_int, hash)]
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub enum PyMatchOp {
     Equal,
     NotEqual,
@@ -405,8 +1289,8 @@ impl PyMatchOp {
     }
 }
 
-#[pyclass(name = "Matcher", module = "promql_parser")]
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+#[pyclass(frozen, name = "Matcher", module = "promql_parser", eq, hash)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct PyMatcher {
     #[pyo3(get)]
     op: PyMatchOp,
@@ -418,41 +1302,167 @@ pub struct PyMatcher {
 
 #[pymethods]
 impl PyMatcher {
+    #[getter]
+    fn unescaped_value(&self) -> String {
+        format::unescape(&self.value)
+    }
+
     fn __repr__(&self) -> String {
         format!(
-            "Matcher({}, \"{}\", {})",
+            "Matcher({}, {}, {})",
             self.op.__repr__(),
-            self.name,
-            self.value
+            format::quote(&self.name),
+            format::quote(&self.unescaped_value())
         )
     }
 }
 
-impl From<promql_parser::label::Matcher> for PyMatcher {
-    fn from(matcher: promql_parser::label::Matcher) -> Self {
+impl From<promql::label::Matcher> for PyMatcher {
+    fn from(matcher: promql::label::Matcher) -> Self {
         PyMatcher {
             name: matcher.name,
             value: matcher.value,
             op: match matcher.op {
-                promql_parser::label::MatchOp::Equal => PyMatchOp::Equal,
-                promql_parser::label::MatchOp::NotEqual => PyMatchOp::NotEqual,
-                promql_parser::label::MatchOp::Re(_) => PyMatchOp::Re,
-                promql_parser::label::MatchOp::NotRe(_) => PyMatchOp::NotRe,
+                promql::label::MatchOp::Equal => PyMatchOp::Equal,
+                promql::label::MatchOp::NotEqual => PyMatchOp::NotEqual,
+                promql::label::MatchOp::Re(_) => PyMatchOp::Re,
+                promql::label::MatchOp::NotRe(_) => PyMatchOp::NotRe,
             },
         }
     }
 }
 
-#[pyclass(name = "Matchers", module = "promql_parser")]
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// Equality and hashing ignore the order of matchers and of `or` groups, as
+/// well as duplicates; see [`PyMatchers::normalized`].
+#[pyclass(frozen, name = "Matchers", module = "promql_parser", eq, hash)]
+#[derive(Debug, Clone)]
 pub struct PyMatchers {
     #[pyo3(get)]
     matchers: Vec<PyMatcher>,
     #[pyo3(get)]
     or_matchers: Vec<Vec<PyMatcher>>,
+    inner: Matchers,
+}
+
+impl PyMatchers {
+    /// The matchers as sorted, deduplicated lists of unescaped values, with
+    /// each `or` group normalized the same way and the groups themselves
+    /// sorted.
+    fn normalized(&self) -> (Vec<PyMatcher>, Vec<Vec<PyMatcher>>) {
+        fn sorted(matchers: &[PyMatcher]) -> Vec<PyMatcher> {
+            let mut matchers: Vec<_> = matchers
+                .iter()
+                .map(|m| PyMatcher {
+                    value: m.unescaped_value(),
+                    ..m.clone()
+                })
+                .collect();
+            matchers.sort();
+            matchers.dedup();
+            matchers
+        }
+        let mut or_matchers: Vec<_> = self.or_matchers.iter().map(|g| sorted(g)).collect();
+        or_matchers.sort();
+        or_matchers.dedup();
+        (sorted(&self.matchers), or_matchers)
+    }
+
+    /// The alternatives a series may satisfy: the `or` groups, or the plain
+    /// matchers as a single group.
+    fn groups(&self) -> Vec<&[PyMatcher]> {
+        if self.or_matchers.is_empty() {
+            vec![&self.matchers]
+        } else {
+            self.or_matchers.iter().map(Vec::as_slice).collect()
+        }
+    }
+}
+
+/// Whether no series can satisfy all of `matchers` at once, judging only by
+/// `=` and `!=` matchers on the same label.
+fn matchers_conflict<'a>(matchers: impl Iterator<Item = &'a PyMatcher> + Clone) -> bool {
+    matchers.clone().enumerate().any(|(i, a)| {
+        matchers.clone().skip(i + 1).any(|b| {
+            if a.name != b.name {
+                return false;
+            }
+            let same_value = format::unescape(&a.value) == format::unescape(&b.value);
+            match (a.op, b.op) {
+                (PyMatchOp::Equal, PyMatchOp::Equal) => !same_value,
+                (PyMatchOp::Equal, PyMatchOp::NotEqual)
+                | (PyMatchOp::NotEqual, PyMatchOp::Equal) => same_value,
+                _ => false,
+            }
+        })
+    })
+}
+
+impl From<Matchers> for PyMatchers {
+    fn from(matchers: Matchers) -> Self {
+        PyMatchers {
+            matchers: matchers.matchers.iter().cloned().map(Into::into).collect(),
+            or_matchers: matchers
+                .or_matchers
+                .iter()
+                .map(|group| group.iter().cloned().map(Into::into).collect())
+                .collect(),
+            inner: matchers,
+        }
+    }
+}
+
+impl PartialEq for PyMatchers {
+    fn eq(&self, other: &Self) -> bool {
+        self.normalized() == other.normalized()
+    }
+}
+
+impl Eq for PyMatchers {}
+
+impl Hash for PyMatchers {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.normalized().hash(state);
+    }
+}
+
+#[pymethods]
+impl PyMatchers {
+    fn as_set<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PySet>> {
+        PySet::new(py, self.matchers.clone())
+    }
+
+    fn or_as_set<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PySet>> {
+        let groups = self
+            .or_matchers
+            .iter()
+            .map(|group| PyFrozenSet::new(py, group.clone()))
+            .collect::<PyResult<Vec<_>>>()?;
+        PySet::new(py, groups)
+    }
+
+    fn by_op(&self, op: PyMatchOp) -> Vec<PyMatcher> {
+        self.matchers
+            .iter()
+            .filter(|m| m.op == op)
+            .cloned()
+            .collect()
+    }
+
+    fn conflicts_with(&self, other: &PyMatchers) -> bool {
+        self.groups().iter().all(|a| {
+            other
+                .groups()
+                .iter()
+                .all(|b| matchers_conflict(a.iter().chain(b.iter())))
+        })
+    }
+
+    fn to_promql(&self) -> String {
+        format!("{{{}}}", format::matchers_to_string(&self.inner))
+    }
 }
 
-#[pyclass(extends = PyExpr, name = "VectorSelector", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "VectorSelector", module = "promql_parser")]
 pub struct PyVectorSelector {
     #[pyo3(get)]
     name: Option<String>,
@@ -464,8 +1474,60 @@ pub struct PyVectorSelector {
     at: Option<PyAtModifier>,
 }
 
+#[pymethods]
+impl PyVectorSelector {
+    #[new]
+    #[pyo3(signature = (name=None, matchers=None, *, offset=None, at=None))]
+    fn new(
+        name: Option<String>,
+        matchers: Option<PyMatchers>,
+        offset: Option<Duration>,
+        at: Option<PyAtModifier>,
+    ) -> PyResult<PyClassInitializer<Self>> {
+        let offset = match offset {
+            Some(offset) if offset < Duration::zero() => Some(Offset::Neg(
+                (-offset)
+                    .to_std()
+                    .map_err(|e| PyOverflowError::new_err(e.to_string()))?,
+            )),
+            Some(offset) if offset > Duration::zero() => Some(Offset::Pos(
+                offset
+                    .to_std()
+                    .map_err(|e| PyOverflowError::new_err(e.to_string()))?,
+            )),
+            _ => None,
+        };
+        let expr = Expr::VectorSelector(VectorSelector {
+            name,
+            matchers: matchers.map_or_else(Matchers::empty, |matchers| matchers.inner),
+            offset,
+            at: at.map(Into::into),
+        });
+        // Round-trip through the parser so the selector is validated exactly
+        // like parsed queries, e.g. rejecting `{}` or an invalid metric name.
+        let expr = parser::parse(&Promql(&expr).to_string()).map_err(PyValueError::new_err)?;
+        let Expr::VectorSelector(expr) = expr else {
+            return Err(PyValueError::new_err("invalid vector selector"));
+        };
+        Self::initializer(expr)
+    }
+
+    fn metric_name(slf: &Bound<'_, Self>) -> Option<String> {
+        let Expr::VectorSelector(vs) = &slf.as_super().get().expr else {
+            unreachable!("VectorSelector always wraps Expr::VectorSelector")
+        };
+        metric_name(vs)
+    }
+}
+
 impl PyVectorSelector {
     fn create(py: Python, expr: VectorSelector) -> PyResult<PyObject> {
+        Py::new(py, Self::initializer(expr)?)?.into_py_any(py)
+    }
+
+    fn initializer(mut expr: VectorSelector) -> PyResult<PyClassInitializer<Self>> {
+        // The parser rejects `offset 0s`, but trees built otherwise may have it.
+        desugar::strip_zero_offset(&mut expr.offset);
         let parent = PyExpr {
             expr: Expr::VectorSelector(expr.clone()),
         };
@@ -475,68 +1537,105 @@ impl PyVectorSelector {
             offset,
             at,
         } = expr;
-        let or_matchers = &matchers.or_matchers;
-        let matchers = &matchers.matchers;
-        let mut py_matchers = PyMatchers {
-            matchers: Vec::with_capacity(matchers.len()),
-            or_matchers: Vec::with_capacity(or_matchers.len()),
-        };
-        for matcher in matchers {
-            py_matchers.matchers.push(matcher.clone().into());
-        }
-        for matchers in or_matchers {
-            py_matchers.or_matchers.push(
-                matchers
-                    .iter()
-                    .map(|matcher| matcher.clone().into())
-                    .collect(),
-            );
-        }
-
         let initializer = PyClassInitializer::from(parent).add_subclass(PyVectorSelector {
             name,
-            matchers: py_matchers,
-            offset: match offset {
-                Some(Offset::Pos(off)) => Some(
-                    Duration::from_std(off).map_err(|e| PyOverflowError::new_err(e.to_string()))?,
-                ),
-                Some(Offset::Neg(off)) => Some(
-                    -Duration::from_std(off)
-                        .map_err(|e| PyOverflowError::new_err(e.to_string()))?,
-                ),
-                None => None,
-            },
+            matchers: matchers.into(),
+            offset: offset.map(offset_timedelta),
             at: at.map(|at| at.into()),
         });
-        Py::new(py, initializer)?.into_py_any(py)
+        Ok(initializer)
     }
 }
 
-#[pyclass(extends = PyExpr, name = "MatrixSelector", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "MatrixSelector", module = "promql_parser")]
 pub struct PyMatrixSelector {
     #[pyo3(get)]
-    vector_selector: PyObject,
+    vector_selector: Py<PyVectorSelector>,
     #[pyo3(get)]
     range: Duration,
 }
 
 impl PyMatrixSelector {
     fn create(py: Python, expr: MatrixSelector) -> PyResult<PyObject> {
+        Py::new(py, Self::initializer(py, expr)?)?.into_py_any(py)
+    }
+
+    fn initializer(py: Python, expr: MatrixSelector) -> PyResult<PyClassInitializer<Self>> {
         let parent = PyExpr {
             expr: Expr::MatrixSelector(expr.clone()),
         };
         let MatrixSelector { vs, range } = expr;
-        let vector_selector = PyVectorSelector::create(py, vs)?;
+        let vector_selector = Py::new(py, PyVectorSelector::initializer(vs)?)?;
         let initializer = PyClassInitializer::from(parent).add_subclass(PyMatrixSelector {
             vector_selector,
-            range: Duration::from_std(range)
-                .map_err(|e| PyOverflowError::new_err(e.to_string()))?,
+            range: timedelta(range),
         });
-        Py::new(py, initializer)?.into_py_any(py)
+        Ok(initializer)
+    }
+}
+
+#[pymethods]
+impl PyMatrixSelector {
+    #[new]
+    fn new(
+        py: Python,
+        vector_selector: &Bound<'_, PyVectorSelector>,
+        range: Duration,
+    ) -> PyResult<PyClassInitializer<Self>> {
+        if range <= Duration::zero() {
+            return Err(PyValueError::new_err("range must be positive"));
+        }
+        // PromQL durations have millisecond precision; anything finer would
+        // not render as the same range, or at all.
+        if range.subsec_nanos() % 1_000_000 != 0 {
+            return Err(PyValueError::new_err(
+                "range must be a whole number of milliseconds",
+            ));
+        }
+        let range = range
+            .to_std()
+            .map_err(|e| PyOverflowError::new_err(e.to_string()))?;
+        let Expr::VectorSelector(vs) = &vector_selector.as_super().get().expr else {
+            unreachable!("VectorSelector always wraps Expr::VectorSelector")
+        };
+        Self::initializer(
+            py,
+            MatrixSelector {
+                vs: vs.clone(),
+                range,
+            },
+        )
+    }
+
+    #[getter]
+    fn name(&self) -> Option<String> {
+        self.vector_selector.get().name.clone()
+    }
+
+    #[getter]
+    fn matchers(&self) -> PyMatchers {
+        self.vector_selector.get().matchers.clone()
+    }
+
+    #[getter]
+    fn offset(&self) -> Option<Duration> {
+        self.vector_selector.get().offset
+    }
+
+    #[getter]
+    fn at(&self) -> Option<PyAtModifier> {
+        self.vector_selector.get().at.clone()
+    }
+
+    fn metric_name(slf: &Bound<'_, Self>) -> Option<String> {
+        let Expr::MatrixSelector(ms) = &slf.as_super().get().expr else {
+            unreachable!("MatrixSelector always wraps Expr::MatrixSelector")
+        };
+        metric_name(&ms.vs)
     }
 }
 
-#[pyclass(extends = PyExpr, name = "Call", module = "promql_parser")]
+#[pyclass(extends = PyExpr, frozen, name = "Call", module = "promql_parser")]
 pub struct PyCall {
     #[pyo3(get)]
     func: PyFunction,
@@ -567,7 +1666,36 @@ impl PyCall {
     }
 }
 
-#[pyclass(name = "ValueType", module = "promql_parser", eq, eq_int)]
+/// A node contributed by a parser extension rather than by PromQL itself.
+#[pyclass(extends = PyExpr, frozen, name = "ExtensionExpr", module = "promql_parser")]
+pub struct PyExtensionExpr {
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    children: Vec<PyObject>,
+}
+
+impl PyExtensionExpr {
+    fn create(py: Python, ext: Extension) -> PyResult<PyObject> {
+        let name = ext.expr.name().to_string();
+        let children: Result<Vec<_>, _> = ext
+            .expr
+            .children()
+            .iter()
+            .map(|child| PyExpr::create(py, child.clone()))
+            .collect();
+        let parent = PyExpr {
+            expr: Expr::Extension(ext),
+        };
+        let initializer = PyClassInitializer::from(parent).add_subclass(PyExtensionExpr {
+            name,
+            children: children?,
+        });
+        Py::new(py, initializer)?.into_py_any(py)
+    }
+}
+
+#[pyclass(frozen, name = "ValueType", module = "promql_parser", eq, eq_int)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PyValueType {
     Vector,
@@ -587,7 +1715,7 @@ impl From<ValueType> for PyValueType {
     }
 }
 
-#[pyclass(name = "Function", module = "promql_parser")]
+#[pyclass(frozen, name = "Function", module = "promql_parser")]
 #[derive(Debug, Clone)]
 pub struct PyFunction {
     #[pyo3(get)]
@@ -599,3 +1727,116 @@ pub struct PyFunction {
     #[pyo3(get)]
     return_type: PyValueType,
 }
+
+/// Lazily parses one query per line, as returned by `parse_lines`.
+#[pyclass(name = "ParseLines", module = "promql_parser")]
+pub struct PyParseLines {
+    text: String,
+    pos: usize,
+    line: usize,
+}
+
+impl PyParseLines {
+    pub fn new(text: String) -> Self {
+        PyParseLines {
+            text,
+            pos: 0,
+            line: 0,
+        }
+    }
+}
+
+#[pymethods]
+impl PyParseLines {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> Option<(usize, PyObject)> {
+        while self.pos < self.text.len() {
+            let rest = &self.text[self.pos..];
+            let (line, len) = match rest.find('\n') {
+                Some(end) => (&rest[..end], end + 1),
+                None => (rest, rest.len()),
+            };
+            self.pos += len;
+            self.line += 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = parser::parse(line)
+                .map_err(PyValueError::new_err)
+                .and_then(|expr| PyExpr::create(py, expr));
+            let result = match result {
+                Ok(expr) => expr,
+                Err(err) => err.into_value(py).into_any(),
+            };
+            return Some((self.line, result));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+    use std::sync::Arc;
+
+    use promql::parser::ast::ExtensionExpr;
+    use promql::parser::{self, value::ValueType, Expr, Extension};
+    use pyo3::prelude::*;
+
+    use super::{PyExpr, PyExtensionExpr, PyValueType};
+
+    /// An extension node such as a downstream parser might produce.
+    #[derive(Debug)]
+    struct Both(Vec<Expr>);
+
+    impl ExtensionExpr for Both {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn name(&self) -> &str {
+            "both"
+        }
+
+        fn value_type(&self) -> ValueType {
+            ValueType::Vector
+        }
+
+        fn children(&self) -> &[Expr] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn wraps_extension_nodes() -> PyResult<()> {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let children = vec![parser::parse("foo").unwrap(), parser::parse("bar").unwrap()];
+            let expr = Expr::Extension(Extension {
+                expr: Arc::new(Both(children.clone())),
+            });
+            let obj = PyExpr::create(py, expr)?;
+            let node = obj.bind(py).downcast::<PyExtensionExpr>()?;
+            assert_eq!(node.get().name, "both");
+            let wrapped = node
+                .get()
+                .children
+                .iter()
+                .map(|child| Ok(child.bind(py).downcast::<PyExpr>()?.get().expr.clone()))
+                .collect::<PyResult<Vec<_>>>()?;
+            assert_eq!(wrapped, children);
+            assert_eq!(node.as_super().get().value_type(), PyValueType::Vector);
+            // There is no PromQL for extension nodes, so `str()` falls back to
+            // their debug output.
+            assert_eq!(
+                node.str()?.to_string(),
+                format!("Extension {{ expr: {:?} }}", Both(children))
+            );
+            Ok(())
+        })
+    }
+}
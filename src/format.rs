@@ -0,0 +1,547 @@
+use std::fmt::{self, Write};
+use std::time::SystemTime;
+
+use promql::label::{Labels, Matcher, Matchers};
+use promql::parser::token::{self, TokenType};
+use promql::parser::{
+    AggregateExpr, AtModifier, BinModifier, BinaryExpr, Call, Expr, LabelModifier, MatrixSelector,
+    NumberLiteral, ParenExpr, StringLiteral, SubqueryExpr, UnaryExpr, VectorMatchCardinality,
+    VectorSelector,
+};
+use promql::util::display_duration;
+
+use crate::walk;
+
+/// Renders an [`Expr`] back to PromQL text.
+///
+/// This follows the layout of `Expr`'s own `Display` impl, except that string
+/// literals and matcher values are re-quoted so that quotes, backslashes and
+/// control characters survive a round-trip through the parser.
+pub struct Promql<'a>(pub &'a Expr);
+
+impl fmt::Display for Promql<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expr::Aggregate(AggregateExpr {
+                op,
+                expr,
+                param,
+                modifier,
+            }) => {
+                write!(f, "{op}")?;
+                match modifier {
+                    Some(LabelModifier::Exclude(ls)) => write!(f, " without ({ls}) ")?,
+                    Some(LabelModifier::Include(ls)) => write!(f, " by ({ls}) ")?,
+                    None => (),
+                }
+                write!(f, "(")?;
+                if let Some(param) = param {
+                    write!(f, "{}, ", Promql(param))?;
+                }
+                write!(f, "{})", Promql(expr))
+            }
+            Expr::Unary(UnaryExpr { expr }) => write!(f, "-{}", Promql(expr)),
+            Expr::Binary(BinaryExpr {
+                op,
+                lhs,
+                rhs,
+                modifier,
+            }) => {
+                write!(f, "{} {op}", Promql(lhs))?;
+                if let Some(modifier) = modifier {
+                    write!(f, "{modifier}")?;
+                }
+                write!(f, " {}", Promql(rhs))
+            }
+            Expr::Paren(ParenExpr { expr }) => write!(f, "({})", Promql(expr)),
+            Expr::Subquery(SubqueryExpr {
+                expr,
+                offset,
+                at,
+                range,
+                step,
+            }) => {
+                write!(f, "{}[{}:", Promql(expr), display_duration(range))?;
+                if let Some(step) = step {
+                    write!(f, "{}", display_duration(step))?;
+                }
+                write!(f, "]")?;
+                if let Some(at) = at {
+                    write!(f, " @ {}", at_to_string(at))?;
+                }
+                if let Some(offset) = offset {
+                    write!(f, " offset {offset}")?;
+                }
+                Ok(())
+            }
+            Expr::NumberLiteral(lit) => write!(f, "{}", number_to_string(lit)),
+            Expr::StringLiteral(StringLiteral { val }) => write!(f, "{}", quote(&unescape(val))),
+            Expr::VectorSelector(vs) => {
+                write_selector(f, vs, None)?;
+                write_modifiers(f, vs)
+            }
+            Expr::MatrixSelector(MatrixSelector { vs, range }) => {
+                write_selector(f, vs, Some(display_duration(range)))?;
+                write_modifiers(f, vs)
+            }
+            Expr::Call(Call { func, args }) => {
+                write!(f, "{}(", func.name)?;
+                for (i, arg) in args.args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", Promql(arg))?;
+                }
+                write!(f, ")")
+            }
+            Expr::Extension(ext) => write!(f, "{ext:?}"),
+        }
+    }
+}
+
+/// The name of an operator or aggregation as written in PromQL. This is
+/// kept here rather than taken from the `Display` of `TokenType`, which
+/// `promql-parser` doesn't promise to keep stable.
+pub fn op_name(op: TokenType) -> Option<&'static str> {
+    let name = match op.id() {
+        token::T_ADD => "+",
+        token::T_SUB => "-",
+        token::T_MUL => "*",
+        token::T_DIV => "/",
+        token::T_MOD => "%",
+        token::T_POW => "^",
+        token::T_ATAN2 => "atan2",
+        token::T_EQLC => "==",
+        token::T_NEQ => "!=",
+        token::T_GTR => ">",
+        token::T_LSS => "<",
+        token::T_GTE => ">=",
+        token::T_LTE => "<=",
+        token::T_LAND => "and",
+        token::T_LOR => "or",
+        token::T_LUNLESS => "unless",
+        token::T_SUM => "sum",
+        token::T_AVG => "avg",
+        token::T_COUNT => "count",
+        token::T_MIN => "min",
+        token::T_MAX => "max",
+        token::T_GROUP => "group",
+        token::T_STDDEV => "stddev",
+        token::T_STDVAR => "stdvar",
+        token::T_TOPK => "topk",
+        token::T_BOTTOMK => "bottomk",
+        token::T_COUNT_VALUES => "count_values",
+        token::T_QUANTILE => "quantile",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Render an operator by [`op_name`], falling back to the upstream
+/// `Display` for one it doesn't know.
+struct OpName(TokenType);
+
+impl fmt::Display for OpName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match op_name(self.0) {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// A concise, stable `repr` of an [`Expr`] built from its public fields.
+///
+/// Unlike the `Debug` impl of `promql_parser`, this doesn't leak compiled
+/// regexes or other internals and doesn't change with upstream releases.
+/// Optional fields are omitted when unset.
+pub struct Repr<'a>(pub &'a Expr);
+
+impl fmt::Display for Repr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expr::Aggregate(AggregateExpr {
+                op,
+                expr,
+                param,
+                modifier,
+            }) => {
+                write!(f, "AggregateExpr(op={}, expr={}", OpName(*op), Repr(expr))?;
+                if let Some(param) = param {
+                    write!(f, ", param={}", Repr(param))?;
+                }
+                match modifier {
+                    Some(LabelModifier::Include(ls)) => write!(f, ", modifier=by({ls})")?,
+                    Some(LabelModifier::Exclude(ls)) => write!(f, ", modifier=without({ls})")?,
+                    None => (),
+                }
+                write!(f, ")")
+            }
+            Expr::Unary(UnaryExpr { expr }) => write!(f, "UnaryExpr(expr={})", Repr(expr)),
+            Expr::Binary(BinaryExpr {
+                op,
+                lhs,
+                rhs,
+                modifier,
+            }) => {
+                write!(
+                    f,
+                    "BinaryExpr(op={}, lhs={}, rhs={}",
+                    OpName(*op),
+                    Repr(lhs),
+                    Repr(rhs)
+                )?;
+                if let Some(modifier) = modifier {
+                    write!(f, ", modifier=")?;
+                    write_bin_modifier(f, modifier)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Paren(ParenExpr { expr }) => write!(f, "ParenExpr(expr={})", Repr(expr)),
+            Expr::Subquery(SubqueryExpr {
+                expr,
+                offset,
+                at,
+                range,
+                step,
+            }) => {
+                write!(
+                    f,
+                    "SubqueryExpr(expr={}, range={}",
+                    Repr(expr),
+                    display_duration(range)
+                )?;
+                if let Some(step) = step {
+                    write!(f, ", step={}", display_duration(step))?;
+                }
+                if let Some(offset) = offset {
+                    write!(f, ", offset={offset}")?;
+                }
+                if let Some(at) = at {
+                    write!(f, ", at={}", at_to_string(at))?;
+                }
+                write!(f, ")")
+            }
+            Expr::NumberLiteral(lit) => {
+                let val = if lit.val.is_nan() {
+                    "nan".to_string()
+                } else if lit.val == f64::INFINITY {
+                    "inf".to_string()
+                } else if lit.val == f64::NEG_INFINITY {
+                    "-inf".to_string()
+                } else {
+                    format!("{:?}", lit.val)
+                };
+                write!(f, "NumberLiteral(val={val})")
+            }
+            Expr::StringLiteral(StringLiteral { val }) => {
+                write!(f, "StringLiteral(val={})", quote(&unescape(val)))
+            }
+            Expr::VectorSelector(vs) => {
+                write!(f, "VectorSelector(")?;
+                write_selector_fields(f, vs)?;
+                write!(f, ")")
+            }
+            Expr::MatrixSelector(MatrixSelector { vs, range }) => {
+                write!(f, "MatrixSelector(vector_selector=VectorSelector(")?;
+                write_selector_fields(f, vs)?;
+                write!(f, "), range={})", display_duration(range))
+            }
+            Expr::Call(Call { func, args }) => {
+                write!(f, "Call(func={}, args=[", func.name)?;
+                for (i, arg) in args.args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", Repr(arg))?;
+                }
+                write!(f, "])")
+            }
+            Expr::Extension(ext) => write!(f, "Extension(name={})", ext.expr.name()),
+        }
+    }
+}
+
+fn write_bin_modifier(f: &mut fmt::Formatter<'_>, modifier: &BinModifier) -> fmt::Result {
+    let card = match &modifier.card {
+        VectorMatchCardinality::OneToOne => "OneToOne".to_string(),
+        VectorMatchCardinality::ManyToOne(ls) => format!("ManyToOne({ls})"),
+        VectorMatchCardinality::OneToMany(ls) => format!("OneToMany({ls})"),
+        VectorMatchCardinality::ManyToMany => "ManyToMany".to_string(),
+    };
+    write!(f, "BinModifier(card={card}")?;
+    match &modifier.matching {
+        Some(LabelModifier::Include(ls)) => write!(f, ", matching=on({ls})")?,
+        Some(LabelModifier::Exclude(ls)) => write!(f, ", matching=ignoring({ls})")?,
+        None => (),
+    }
+    let return_bool = if modifier.return_bool {
+        "True"
+    } else {
+        "False"
+    };
+    write!(f, ", return_bool={return_bool})")
+}
+
+fn write_selector_fields(f: &mut fmt::Formatter<'_>, vs: &VectorSelector) -> fmt::Result {
+    let mut fields = Vec::new();
+    if let Some(name) = &vs.name {
+        fields.push(format!("name={}", quote(name)));
+    }
+    let matchers = matchers_to_string(&vs.matchers);
+    if !matchers.is_empty() {
+        fields.push(format!("matchers={{{matchers}}}"));
+    }
+    if let Some(offset) = &vs.offset {
+        fields.push(format!("offset={offset}"));
+    }
+    if let Some(at) = &vs.at {
+        fields.push(format!("at={}", at_to_string(at)));
+    }
+    write!(f, "{}", fields.join(", "))
+}
+
+/// Format a number literal compactly, like Prometheus does: the shortest
+/// representation that round-trips, switching to exponent notation for very
+/// large or small magnitudes (`1e300` rather than 301 digits).
+///
+/// The parser keeps only the value, so `0x10`, `1.6e1` and `16` all render
+/// as `16`.
+pub fn number_to_string(lit: &NumberLiteral) -> String {
+    let val = lit.val;
+    if !val.is_finite() || val == 0.0 {
+        return lit.to_string();
+    }
+    let exp = format!("{val:e}");
+    let power: i32 = exp[exp.find('e').expect("exponent notation") + 1..]
+        .parse()
+        .expect("valid exponent");
+    if (-4..21).contains(&power) {
+        lit.to_string()
+    } else {
+        exp
+    }
+}
+
+pub fn at_to_string(at: &AtModifier) -> String {
+    match at {
+        AtModifier::Start => "start()".to_string(),
+        AtModifier::End => "end()".to_string(),
+        AtModifier::At(time) => format!("{:.3}", epoch_seconds(time)),
+    }
+}
+
+/// Seconds since the Unix epoch, negative for times before it.
+pub fn epoch_seconds(time: &SystemTime) -> f64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+fn write_selector(
+    f: &mut fmt::Formatter<'_>,
+    vs: &VectorSelector,
+    range: Option<String>,
+) -> fmt::Result {
+    if let Some(name) = &vs.name {
+        write!(f, "{name}")?;
+    }
+    let matchers = matchers_to_string(&vs.matchers);
+    if !matchers.is_empty() {
+        write!(f, "{{{matchers}}}")?;
+    }
+    if let Some(range) = range {
+        write!(f, "[{range}]")?;
+    }
+    Ok(())
+}
+
+fn write_modifiers(f: &mut fmt::Formatter<'_>, vs: &VectorSelector) -> fmt::Result {
+    if let Some(at) = &vs.at {
+        write!(f, " @ {}", at_to_string(at))?;
+    }
+    if let Some(offset) = &vs.offset {
+        write!(f, " offset {offset}")?;
+    }
+    Ok(())
+}
+
+/// Render `expr` in a canonical form suitable as a cache key.
+///
+/// On top of the [`Promql`] rendering, label lists in `by`/`without`,
+/// `on`/`ignoring` and `group_left`/`group_right` are sorted and deduplicated,
+/// and matchers are sorted, both within each `or` group and across groups.
+pub fn canonical(expr: &Expr) -> String {
+    let mut expr = expr.clone();
+    walk::visit_mut(&mut expr, &mut |expr| match expr {
+        Expr::Aggregate(AggregateExpr { modifier, .. }) => {
+            // `sum by () (x)` aggregates everything, just like `sum(x)`.
+            if matches!(modifier, Some(LabelModifier::Include(ls)) if ls.is_empty()) {
+                *modifier = None;
+            }
+            if let Some(modifier) = modifier {
+                sort_label_modifier(modifier);
+            }
+        }
+        Expr::Binary(BinaryExpr {
+            modifier: Some(modifier),
+            ..
+        }) => {
+            // `a + ignoring () b` matches on all labels, just like `a + b`.
+            if matches!(&modifier.matching, Some(LabelModifier::Exclude(ls)) if ls.is_empty()) {
+                modifier.matching = None;
+            }
+            if let Some(matching) = &mut modifier.matching {
+                sort_label_modifier(matching);
+            }
+            match &mut modifier.card {
+                VectorMatchCardinality::ManyToOne(ls) | VectorMatchCardinality::OneToMany(ls) => {
+                    sort_labels(ls)
+                }
+                VectorMatchCardinality::OneToOne | VectorMatchCardinality::ManyToMany => (),
+            }
+        }
+        Expr::VectorSelector(vs) => sort_matchers(&mut vs.matchers),
+        Expr::MatrixSelector(ms) => sort_matchers(&mut ms.vs.matchers),
+        _ => (),
+    });
+    Promql(&expr).to_string()
+}
+
+fn sort_label_modifier(modifier: &mut LabelModifier) {
+    match modifier {
+        LabelModifier::Include(ls) | LabelModifier::Exclude(ls) => sort_labels(ls),
+    }
+}
+
+fn sort_labels(labels: &mut Labels) {
+    labels.labels.sort();
+    labels.labels.dedup();
+}
+
+fn sort_matchers(matchers: &mut Matchers) {
+    matchers.matchers.sort_by_cached_key(matcher_to_string);
+    for group in &mut matchers.or_matchers {
+        group.sort_by_cached_key(matcher_to_string);
+    }
+    matchers
+        .or_matchers
+        .sort_by_cached_key(|group| group.iter().map(matcher_to_string).collect::<Vec<_>>());
+}
+
+/// Render the matchers of a selector without the surrounding braces.
+///
+/// Plain matchers are sorted like upstream does; `or` groups keep their order.
+pub fn matchers_to_string(matchers: &Matchers) -> String {
+    if matchers.or_matchers.is_empty() {
+        let mut parts: Vec<String> = matchers.matchers.iter().map(matcher_to_string).collect();
+        parts.sort();
+        parts.join(",")
+    } else {
+        matchers
+            .or_matchers
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(matcher_to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+}
+
+pub fn matcher_to_string(matcher: &Matcher) -> String {
+    format!(
+        "{}{}{}",
+        matcher.name,
+        matcher.op,
+        quote(&unescape(&matcher.value))
+    )
+}
+
+/// Quote `val` as a double-quoted PromQL string literal.
+pub fn quote(val: &str) -> String {
+    let mut s = String::with_capacity(val.len() + 2);
+    s.push('"');
+    for ch in val.chars() {
+        match ch {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            '\x07' => s.push_str("\\a"),
+            '\x08' => s.push_str("\\b"),
+            '\x0b' => s.push_str("\\v"),
+            '\x0c' => s.push_str("\\f"),
+            ch if ch.is_control() => write!(s, "\\u{:04x}", ch as u32).unwrap(),
+            ch => s.push(ch),
+        }
+    }
+    s.push('"');
+    s
+}
+
+/// Interpret the escape sequences of a string as the lexer leaves it.
+///
+/// `promql_parser` keeps the text between the quotes verbatim, so `"a\"b"`
+/// is stored as `a\"b`. Unknown escape sequences are kept as written.
+pub fn unescape(raw: &str) -> String {
+    let mut s = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(pos) = rest.find('\\') {
+        s.push_str(&rest[..pos]);
+        let seq = &rest[pos + 1..];
+        match decode_escape(seq) {
+            Some((ch, len)) => {
+                s.push(ch);
+                rest = &seq[len..];
+            }
+            None => {
+                s.push('\\');
+                rest = seq;
+            }
+        }
+    }
+    s.push_str(rest);
+    s
+}
+
+/// Decode the escape sequence following a backslash, returning the character
+/// and the number of bytes consumed.
+fn decode_escape(seq: &str) -> Option<(char, usize)> {
+    let first = seq.chars().next()?;
+    let simple = match first {
+        'a' => Some('\x07'),
+        'b' => Some('\x08'),
+        'f' => Some('\x0c'),
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        'v' => Some('\x0b'),
+        '\\' | '"' | '\'' | '`' => Some(first),
+        _ => None,
+    };
+    if let Some(ch) = simple {
+        return Some((ch, first.len_utf8()));
+    }
+    let (start, digits, radix) = match first {
+        'x' => (1, 2, 16),
+        'u' => (1, 4, 16),
+        'U' => (1, 8, 16),
+        '0'..='7' => (0, 3, 8),
+        _ => return None,
+    };
+    let code = seq.get(start..start + digits)?;
+    if !code.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let ch = u32::from_str_radix(code, radix)
+        .ok()
+        .and_then(char::from_u32)?;
+    Some((ch, start + digits))
+}
@@ -1,21 +1,113 @@
+use lrpar::{Lexeme, Lexer, NonStreamingLexer};
+use promql::parser::token::{self, TokenType};
 use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
 use pyo3::types::{PyDelta, PyDeltaAccess};
 
-mod expr;
+mod cache;
+mod desugar;
+mod diff;
+mod explain;
+pub mod expr;
+mod format;
+mod walk;
 
-use self::expr::PyExpr;
+use self::expr::{PyExpr, PyParseLines, PyToken, Query};
 
 /// Parse the input PromQL and return the AST.
 #[pyfunction]
-fn parse(py: Python, input: &str) -> PyResult<PyObject> {
-    PyExpr::parse(py, input)
+#[pyo3(signature = (input, *, max_length=None, max_matchers=None, desugar=false))]
+fn parse(
+    py: Python,
+    input: Query,
+    max_length: Option<usize>,
+    max_matchers: Option<usize>,
+    desugar: bool,
+) -> PyResult<PyObject> {
+    PyExpr::parse(py, input, max_length, max_matchers, desugar)
+}
+
+/// Like `parse`, but reuses the AST of recently parsed identical queries.
+#[pyfunction]
+fn parse_cached(py: Python, input: Query) -> PyResult<PyObject> {
+    cache::parse(py, input.as_str()?)
+}
+
+/// Set how many queries `parse_cached` keeps, evicting the least recently used.
+#[pyfunction]
+fn set_parse_cache_size(size: usize) {
+    cache::set_capacity(size);
+}
+
+/// Drop all queries cached by `parse_cached`.
+#[pyfunction]
+fn clear_parse_cache() {
+    cache::clear();
+}
+
+/// Describe the node-level differences between two expressions.
+#[pyfunction]
+#[pyo3(name = "diff")]
+fn diff_exprs(a: &Bound<'_, PyExpr>, b: &Bound<'_, PyExpr>) -> Vec<String> {
+    diff::diff(&a.get().expr, &b.get().expr)
+}
+
+/// Parse one query per line, skipping blank lines and `#` comments.
+#[pyfunction]
+fn parse_lines(text: String) -> PyParseLines {
+    PyParseLines::new(text)
+}
+
+/// Split the input PromQL into tokens without parsing it.
+#[pyfunction]
+fn tokenize(input: Query) -> PyResult<Vec<PyToken>> {
+    let input = input.as_str()?;
+    let text = close_parens(input);
+    let lexer = promql::parser::lexer(&text).map_err(PyValueError::new_err)?;
+    let tokens = lexer
+        .iter()
+        .flatten()
+        // Drop the closing parentheses added above and the end-of-input marker.
+        .filter(|lexeme| lexeme.tok_id() != token::T_EOF && lexeme.span().start() < input.len())
+        .map(|lexeme| {
+            let span = lexeme.span();
+            PyToken::new(
+                TokenType::new(lexeme.tok_id()),
+                lexer.span_str(span),
+                span.start(),
+                span.end(),
+            )
+        })
+        .collect();
+    Ok(tokens)
+}
+
+/// `input` with enough `)` appended to close its open parentheses, since the
+/// lexer rejects a query that is still being typed, such as `sum(rate(foo`.
+fn close_parens(input: &str) -> String {
+    let closed = |n: usize| format!("{input}{}", ")".repeat(n));
+    let unclosed = |n: usize| matches!(promql::parser::lexer(&closed(n)), Err(e) if e == "unclosed left parenthesis");
+    if !unclosed(0) {
+        return input.to_string();
+    }
+    // Too few parentheses are reported as unclosed and too many as unexpected,
+    // so search for the smallest count that isn't unclosed.
+    let (mut low, mut high) = (1, input.matches('(').count());
+    while low < high {
+        let mid = (low + high) / 2;
+        if unclosed(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    closed(low)
 }
 
 #[pyfunction]
 fn parse_duration<'p>(py: Python<'p>, duration: &str) -> PyResult<Bound<'p, PyDelta>> {
     let duration =
-        ::promql_parser::util::duration::parse_duration(duration).map_err(PyValueError::new_err)?;
+        promql::util::duration::parse_duration(duration).map_err(PyValueError::new_err)?;
     PyDelta::new(
         py,
         0,
@@ -31,7 +123,22 @@ fn display_duration(delta: Bound<'_, PyDelta>) -> String {
         delta.get_days() as u64 * 24 * 60 * 60 + delta.get_seconds() as u64,
         delta.get_microseconds() as u32 * 1000,
     );
-    ::promql_parser::util::duration::display_duration(&duration)
+    promql::util::duration::display_duration(&duration)
+}
+
+/// Format any non-negative timedelta the way the parser formats ranges and offsets.
+#[pyfunction]
+fn duration_to_promql(delta: chrono::Duration) -> PyResult<String> {
+    let duration = delta
+        .to_std()
+        .map_err(|_| PyValueError::new_err("duration must not be negative"))?;
+    // Would otherwise render as an empty string.
+    if !duration.is_zero() && duration.as_millis() == 0 {
+        return Err(PyValueError::new_err(
+            "duration must be zero or at least 1ms",
+        ));
+    }
+    Ok(promql::util::duration::display_duration(&duration))
 }
 
 /// A Python module implemented in Rust.
@@ -40,6 +147,7 @@ fn promql_parser(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<PyExpr>()?;
     m.add_class::<expr::PyAggregateExpr>()?;
     m.add_class::<expr::PyTokenType>()?;
+    m.add_class::<PyToken>()?;
     m.add_class::<expr::PyAggModifier>()?;
     m.add_class::<expr::PyAggModifierType>()?;
     m.add_class::<expr::PyUnaryExpr>()?;
@@ -60,10 +168,19 @@ fn promql_parser(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<expr::PyVectorSelector>()?;
     m.add_class::<expr::PyMatrixSelector>()?;
     m.add_class::<expr::PyCall>()?;
+    m.add_class::<expr::PyExtensionExpr>()?;
     m.add_class::<expr::PyValueType>()?;
     m.add_class::<expr::PyFunction>()?;
+    m.add_class::<PyParseLines>()?;
     m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_cached, m)?)?;
+    m.add_function(wrap_pyfunction!(set_parse_cache_size, m)?)?;
+    m.add_function(wrap_pyfunction!(clear_parse_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(diff_exprs, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_lines, m)?)?;
+    m.add_function(wrap_pyfunction!(tokenize, m)?)?;
     m.add_function(wrap_pyfunction!(parse_duration, m)?)?;
     m.add_function(wrap_pyfunction!(display_duration, m)?)?;
+    m.add_function(wrap_pyfunction!(duration_to_promql, m)?)?;
     Ok(())
 }
@@ -0,0 +1,71 @@
+use promql::parser::{
+    AggregateExpr, BinaryExpr, Call, Expr, Extension, ParenExpr, SubqueryExpr, UnaryExpr,
+};
+
+/// Return the direct children of `expr` in source order.
+///
+/// Unlike `promql::util::walk_expr`, this includes the parameter of
+/// aggregations such as `topk(5, ...)` or `quantile(0.9, ...)`.
+pub fn children(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Aggregate(AggregateExpr { expr, param, .. }) => match param {
+            Some(param) => vec![param.as_ref(), expr.as_ref()],
+            None => vec![expr.as_ref()],
+        },
+        Expr::Unary(UnaryExpr { expr }) => vec![expr.as_ref()],
+        Expr::Binary(BinaryExpr { lhs, rhs, .. }) => vec![lhs.as_ref(), rhs.as_ref()],
+        Expr::Paren(ParenExpr { expr }) => vec![expr.as_ref()],
+        Expr::Subquery(SubqueryExpr { expr, .. }) => vec![expr.as_ref()],
+        Expr::Call(Call { args, .. }) => args.args.iter().map(|arg| arg.as_ref()).collect(),
+        Expr::Extension(Extension { expr }) => expr.children().iter().collect(),
+        Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::VectorSelector(_)
+        | Expr::MatrixSelector(_) => Vec::new(),
+    }
+}
+
+/// Return whether `f` holds for `expr` or any of its descendants, visiting
+/// nodes in pre-order and stopping at the first match.
+pub fn any<'a, F>(expr: &'a Expr, f: &mut F) -> bool
+where
+    F: FnMut(&'a Expr) -> bool,
+{
+    f(expr) || children(expr).into_iter().any(|child| any(child, f))
+}
+
+/// Like [`children`], but mutable.
+///
+/// The children of extension nodes are shared behind an `Arc` and are
+/// therefore not returned.
+pub fn children_mut(expr: &mut Expr) -> Vec<&mut Expr> {
+    match expr {
+        Expr::Aggregate(AggregateExpr { expr, param, .. }) => match param {
+            Some(param) => vec![param.as_mut(), expr.as_mut()],
+            None => vec![expr.as_mut()],
+        },
+        Expr::Unary(UnaryExpr { expr }) => vec![expr.as_mut()],
+        Expr::Binary(BinaryExpr { lhs, rhs, .. }) => vec![lhs.as_mut(), rhs.as_mut()],
+        Expr::Paren(ParenExpr { expr }) => vec![expr.as_mut()],
+        Expr::Subquery(SubqueryExpr { expr, .. }) => vec![expr.as_mut()],
+        Expr::Call(Call { args, .. }) => args.args.iter_mut().map(|arg| arg.as_mut()).collect(),
+        Expr::Extension(_)
+        | Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::VectorSelector(_)
+        | Expr::MatrixSelector(_) => Vec::new(),
+    }
+}
+
+/// Apply `f` to `expr` and then to each of its descendants.
+///
+/// Children are looked up after `f` has run, so `f` may replace the node.
+pub fn visit_mut<F>(expr: &mut Expr, f: &mut F)
+where
+    F: FnMut(&mut Expr),
+{
+    f(expr);
+    for child in children_mut(expr) {
+        visit_mut(child, f);
+    }
+}
@@ -14,3 +14,386 @@ print(parse('1 + 2/(3*1)').prettify())
 print(parse('+some_metric'))
 
 print(promql_parser.display_duration(promql_parser.parse_duration('4w4d2h59m120s')))
+
+# AST nodes are frozen, so parsing and walking shared trees from many threads
+# must be safe, including on free-threaded builds.
+from concurrent.futures import ThreadPoolExecutor
+
+shared = parse('sum by (job) (rate(http_requests_total{code=~"5.."}[5m] offset 1m)) / on(job) group_left sum(up)')
+
+
+def walk(node):
+    count = 1
+    for attr in ("expr", "lhs", "rhs", "param", "vector_selector"):
+        child = getattr(node, attr, None)
+        if isinstance(child, promql_parser.Expr):
+            count += walk(child)
+    for arg in getattr(node, "args", []):
+        count += walk(arg)
+    return count
+
+
+def work(i):
+    local = parse(f'max_over_time(foo{{instance="{i}"}}[5m:1m])')
+    return walk(shared), walk(local), shared.prettify()
+
+
+with ThreadPoolExecutor(max_workers=16) as pool:
+    results = list(pool.map(work, range(1000)))
+assert len(set(results)) == 1
+
+assert parse('sum(rate(foo[5m:] @ 100))').contains_at_modifier()
+assert not parse('sum(rate(foo[5m:] @ 100))').contains_offset()
+assert parse('topk(scalar(bar offset 5m), foo)').contains_offset()
+
+lit = parse('"a\\"b"')
+assert lit.val == 'a\\"b' and lit.unescaped_val == 'a"b'
+assert str(lit) == '"a\\"b"'
+assert parse(str(lit)).unescaped_val == 'a"b'
+assert parse(str(parse("'it\\'s'"))).unescaped_val == "it's"
+assert parse(str(parse('label_replace(up, "dst", "x\\\\y\\n", "src", "(.*)")'))).args[2].unescaped_val == 'x\\y\n'
+matcher = parse('foo{job="a\\"b"}').matchers.matchers[0]
+assert matcher.value == lit.val and matcher.unescaped_value == lit.unescaped_val
+
+assert repr(parse('sum by (job) (rate(foo{code="200"}[5m]))')) == (
+    'AggregateExpr(op=sum, expr=Call(func=rate, args=[MatrixSelector('
+    'vector_selector=VectorSelector(name="foo", matchers={code="200"}), range=5m)]), '
+    'modifier=by(job))'
+)
+assert repr(parse('a unless b')).startswith('BinaryExpr(op=unless, ')
+assert repr(parse('count_values("v", a)')).startswith('AggregateExpr(op=count_values, ')
+
+assert promql_parser.Expr.parse_vector('sum(up)').value_type() == promql_parser.ValueType.Vector
+assert promql_parser.Expr.parse_scalar('1 + 2').value_type() == promql_parser.ValueType.Scalar
+try:
+    promql_parser.Expr.parse_vector('up[5m]')
+except ValueError as e:
+    assert 'got matrix' in str(e)
+else:
+    raise AssertionError('parse_vector accepted a range vector')
+
+from datetime import timedelta
+
+ms = promql_parser.MatrixSelector(parse('foo{bar="baz"}'), timedelta(minutes=5))
+assert str(ms) == 'foo{bar="baz"}[5m]'
+for bad in (timedelta(microseconds=500), timedelta(milliseconds=1, microseconds=500)):
+    try:
+        promql_parser.MatrixSelector(parse('foo'), bad)
+    except ValueError:
+        pass
+    else:
+        raise AssertionError(f'sub-millisecond range {bad} accepted')
+
+modifier = parse('foo > bool on(job, instance) bar').modifier
+assert modifier.return_bool
+assert modifier.on_labels() == ['job', 'instance']
+assert modifier.ignoring_labels() == []
+
+canonical = parse("sum without(b, a) (max_over_time(foo{z='1', a=~\"x\"}[60s:30s]))").canonical()
+assert canonical == 'sum without (a, b) (max_over_time(foo{a=~"x",z="1"}[1m:30s]))'
+assert parse(canonical).canonical() == canonical
+
+assert promql_parser.duration_to_promql(timedelta(minutes=90)) == '1h30m'
+assert promql_parser.duration_to_promql(timedelta(0)) == '0s'
+assert promql_parser.duration_to_promql(timedelta(milliseconds=1, microseconds=500)) == '1ms'
+try:
+    promql_parser.duration_to_promql(timedelta(microseconds=500))
+except ValueError:
+    pass
+else:
+    raise AssertionError('sub-millisecond duration accepted')
+
+assert repr(promql_parser.parse(b'rate(foo[5m])')) == repr(promql_parser.parse('rate(foo[5m])'))
+assert repr(promql_parser.parse(bytearray(b'foo'))) == repr(promql_parser.parse('foo'))
+try:
+    promql_parser.parse(b'\xff')
+    assert False, 'invalid UTF-8 must be rejected'
+except ValueError:
+    pass
+
+a = promql_parser.parse('foo{a="1", b!="2"}').matchers
+b = promql_parser.parse('foo{b!="2", a="1"}').matchers
+assert a == b and hash(a) == hash(b)
+assert a.as_set() - promql_parser.parse('foo{a="1"}').matchers.as_set() == {
+    m for m in a.matchers if m.name == 'b'
+}
+assert promql_parser.parse('{a="1" or b="2", c="3"}').matchers == promql_parser.parse('{c="3", b="2" or a="1"}').matchers
+assert parse('foo{a="\\x41"}').matchers == parse('foo{a="A"}').matchers
+assert hash(parse('foo{a="\\x41"}').matchers) == hash(parse('foo{a="A"}').matchers)
+
+expr = promql_parser.parse('sum(rate(foo[5m])) + 1')
+assert expr.node_count() == 5
+assert expr.complexity() == 9
+
+assert promql_parser.parse('topk(5, foo)').op_name == 'topk'
+assert promql_parser.parse('foo and bar').op_name == 'and'
+assert promql_parser.parse('foo >= bool 1').op_name == '>='
+assert promql_parser.parse('foo != bar').op_name == '!='
+assert promql_parser.parse('count_values("v", foo)').op_name == 'count_values'
+assert promql_parser.parse('foo ^ 2').op_name == '^'
+
+expr = promql_parser.parse('rate(foo[5m] offset 1h) + bar @ 1700000000 + max_over_time(baz[10m:1m] @ end())')
+assert str(expr.without_time_modifiers()) == 'rate(foo[5m]) + bar + max_over_time(baz[10m:1m])'
+assert expr.contains_offset() and not expr.without_time_modifiers().contains_at_modifier()
+
+from datetime import datetime, timezone
+expr = promql_parser.parse('rate(foo[5m]) / max_over_time(bar[10m:1m])')
+pinned = expr.with_at(datetime(2023, 11, 14, 22, 13, 20, tzinfo=timezone.utc))
+assert pinned.lhs.args[0].vector_selector.at.at == datetime(2023, 11, 14, 22, 13, 20, tzinfo=timezone.utc)
+assert pinned.rhs.args[0].at.type == promql_parser.AtModifierType.At
+assert pinned.rhs.args[0].expr.at is None
+
+expr = promql_parser.parse('holt_winters(foo[5m], 0.5, 0.5) + histogram_count(bar) + holt_winters(baz[5m], 0.1, 0.1)')
+assert expr.deprecated_functions() == ['holt_winters', 'histogram_count']
+assert promql_parser.parse('rate(foo[5m])').deprecated_functions() == []
+
+tokens = promql_parser.tokenize('sum(rate(foo[5m]')
+assert [t.text for t in tokens] == ['sum', '(', 'rate', '(', 'foo', '[', '5m', ']']
+assert str(tokens[0].kind) == 'sum'
+assert (tokens[2].start, tokens[2].end) == (4, 8)
+assert [t.text for t in promql_parser.tokenize('foo + (bar')] == ['foo', '+', '(', 'bar']
+assert [t.text for t in promql_parser.tokenize('foo')] == ['foo']
+try:
+    promql_parser.tokenize('foo{job="api')
+except ValueError:
+    pass
+else:
+    raise AssertionError('unterminated string accepted')
+
+assert promql_parser.parse('up{job="api"}').matchers.to_promql() == '{job="api"}'
+assert promql_parser.parse('{a="1" or b="2"}').matchers.to_promql() == '{a="1" or b="2"}'
+
+assert promql_parser.parse('foo{a="1", b="2"}', max_length=100, max_matchers=2).name == 'foo'
+for kwargs in ({'max_length': 5}, {'max_matchers': 1}):
+    try:
+        promql_parser.parse('foo{a="1", b="2"}', **kwargs)
+        assert False, f'{kwargs} must be enforced'
+    except ValueError as e:
+        assert next(iter(kwargs)) in str(e)
+
+expr = promql_parser.parse('sum by (job) (rate(foo{tenant="a"}[5m])) / on (job) group_left (env) bar{b="1" or a="2"}')
+assert expr.label_names_used() == ['__name__', 'a', 'b', 'env', 'job', 'tenant']
+assert parse('foo').label_names_used() == parse('{__name__="foo"}').label_names_used() == ['__name__']
+
+assert parse('max_over_time(foo[10m:])').args[0].effective_step(timedelta(seconds=15)) == timedelta(seconds=15)
+assert parse('max_over_time(foo[10m:1m])').args[0].effective_step(timedelta(seconds=15)) == timedelta(minutes=1)
+
+assert parse('up{job="api"} offset 5m').is_simple_selector()
+assert not any(parse(q).is_simple_selector() for q in ('up[5m]', '(up)', 'sum(up)', 'up + 1'))
+
+expr = parse('foo @ 1700000000.250')
+assert expr.at.timestamp() == 1700000000.25
+assert str(expr) == 'foo @ 1700000000.250'
+assert parse('foo @ end()').at.timestamp() is None
+
+expr = promql_parser.BinaryExpr('/', parse('a + b'), parse('sum(c)'))
+assert str(expr) == '(a + b) / sum(c)'
+assert repr(parse(str(expr))) == repr(expr)
+assert str(promql_parser.BinaryExpr('>', parse('sum(x)'), parse('5'), return_bool=True)) == 'sum(x) > bool 5'
+for args in (('>', parse('1'), parse('2')), ('and', parse('1'), parse('foo')), ('@', parse('a'), parse('b'))):
+    try:
+        promql_parser.BinaryExpr(*args)
+        assert False, f'{args} must be rejected'
+    except ValueError:
+        pass
+assert str(promql_parser.BinaryExpr('^', parse('-1'), parse('2'))) == '(-1) ^ 2'
+assert str(promql_parser.BinaryExpr('*', parse('-1'), parse('-2'))) == '(-1) * -2'
+
+m = lambda q: parse(q).matchers
+assert m('{tenant="a"}').conflicts_with(m('foo{tenant="b"}'))
+assert m('{tenant="a"}').conflicts_with(m('foo{tenant!="a"}'))
+assert not m('{tenant="a"}').conflicts_with(m('foo{tenant=~"b"}'))
+assert not m('{tenant="a"}').conflicts_with(m('{tenant="b" or job="x"}'))
+
+assert not parse('foo').drops_metric_name()
+assert not parse('sort(label_replace(foo, "a", "$1", "b", "(.*)"))').drops_metric_name()
+assert not parse('foo > 1').drops_metric_name()
+assert not parse('topk(3, foo)').drops_metric_name()
+assert all(parse(q).drops_metric_name() for q in ('rate(foo[5m])', 'foo + 1', 'foo > bool 1', 'sum(foo)', '-foo'))
+
+expr = parse('sum(rate(foo[5m])) / bar')
+assert [str(e) for e in expr.find_all('VectorSelector')] == ['foo', 'bar']
+assert [type(e).__name__ for e in expr.find_all('*Selector')] == ['MatrixSelector', 'VectorSelector', 'VectorSelector']
+try:
+    expr.find_all('Selector')
+    assert False, 'unknown node types must be rejected'
+except ValueError as e:
+    assert 'VectorSelector' in str(e)
+
+assert parse('sum(rate(x[60s]))') == parse('sum(rate(x[1m]))')
+assert parse('foo') != parse('(foo)')
+assert len({parse('foo'), parse('foo'), parse('bar')}) == 2
+assert parse('sum(rate(x[5m]))').equals_query('sum( rate(x[5m]) )')
+assert not parse('foo').equals_query('foo{')
+
+assert parse('foo{a="1"}').metric_name() == 'foo'
+assert parse('{__name__="foo", a="1"}').metric_name() == 'foo'
+assert parse('{__name__=~"foo.*"}').metric_name() is None
+
+results = list(promql_parser.parse_lines('# recording rules\nfoo\n\n  sum(bar)\r\nfoo{\n'))
+assert [(n, str(r)) for n, r in results[:2]] == [(2, 'foo'), (4, 'sum(bar)')]
+assert results[2][0] == 5 and isinstance(results[2][1], ValueError)
+
+assert parse('sum by (job) (rate(http_requests_total[5m]))').explain() == '5m rate of http_requests_total, summed by job'
+assert parse('max_over_time(up{job="api"}[1h]) > 0').explain() == 'max over 1h of up with job="api" where greater than 0'
+
+expr = parse('{__name__="foo", job="api"}[5m] offset 1m')
+assert expr.name is None and expr.metric_name() == 'foo'
+assert expr.matchers == expr.vector_selector.matchers
+assert expr.offset == timedelta(minutes=1) and expr.at is None
+
+promql_parser.set_parse_cache_size(2)
+foo = promql_parser.parse_cached('foo')
+assert foo == parse('foo') and promql_parser.parse_cached('foo') is foo
+bar = promql_parser.parse_cached('bar')
+promql_parser.parse_cached('foo')
+promql_parser.parse_cached('baz')  # evicts `bar`, the least recently used
+assert promql_parser.parse_cached('foo') is foo
+assert promql_parser.parse_cached('bar') is not bar
+promql_parser.clear_parse_cache()
+assert promql_parser.parse_cached('foo') is not foo
+promql_parser.set_parse_cache_size(0)
+assert promql_parser.parse_cached('foo') is not promql_parser.parse_cached('foo')
+promql_parser.set_parse_cache_size(128)
+
+assert parse('sum(x)').modifier is None
+by_empty = parse('sum by () (x)')
+assert by_empty.modifier.type == promql_parser.AggModifierType.By and by_empty.modifier.labels == []
+assert parse('sum without () (x)').modifier.type == promql_parser.AggModifierType.Without
+assert parse(str(by_empty)) == by_empty
+assert by_empty.canonical() == parse('sum(x)').canonical()
+assert parse('a + ignoring () b').canonical() == parse('a + b').canonical() == 'a + b'
+assert parse('a + on () b').canonical() == 'a + on () b'
+
+assert [e.kind() for e in (parse('sum(x)'), parse('x + 1'), parse('rate(x[5m])'), parse('x'))] == ['Aggregate', 'Binary', 'Call', 'VectorSelector']
+for q in ('sum(x)', '-x', 'x + 1', '(x)', 'x[5m:]', '1', '"s"', 'x', 'x[5m]', 'rate(x[5m])'):
+    e = parse(q)
+    assert e.kind() == type(e).__name__.removesuffix('Expr'), q
+    assert e.find_all(type(e).__name__)[0] == e, q
+
+# `parse` rejects `offset 0s` outright; built selectors drop a zero offset.
+try:
+    parse('up offset 0s')
+except ValueError:
+    pass
+else:
+    raise AssertionError('offset 0s accepted')
+zero = promql_parser.VectorSelector('up', offset=timedelta(0))
+assert zero.offset is None and str(zero) == 'up'
+
+expr = parse('sum by (job) (rate(foo[5m])) / on (job) bar')
+assert parse('a + b').lhs.prettify() == 'a'
+assert expr.rhs.prettify() == 'bar'
+assert expr.lhs.expr.prettify() == 'rate(foo[5m])'
+assert expr.lhs.expr.args[0].prettify() == 'foo[5m]'
+
+assert str(parse('((sum((rate((foo[5m]))))))', desugar=True)) == 'sum(rate(foo[5m]))'
+assert str(parse('(a + b) * (c) - (-1)', desugar=True)) == '(a + b) * c - (-1)'
+assert str(parse('(a + b)')) == '(a + b)'
+
+assert promql_parser.diff(parse('rate(foo{job="api"}[5m])'), parse('rate(foo{job="web"}[10m])')) == [
+    'expr.args[0]: range changed from 5m to 10m',
+    'expr.args[0].vector_selector: matcher job changed from job="api" to job="web"',
+]
+assert promql_parser.diff(parse('sum(a) + b'), parse('avg by (x) (a) + 1')) == [
+    'expr.lhs: operator changed from sum to avg',
+    'expr.lhs: grouping changed from none to by (x)',
+    'expr.rhs: `b` replaced by `1`',
+]
+assert promql_parser.diff(parse('foo'), parse('foo')) == []
+
+selectors = parse('rate(foo{a="1"}[5m]) / max_over_time({__name__="bar"}[10m:1m])').selectors()
+assert [name for name, _ in selectors] == ['foo', 'bar']
+assert selectors[0][1] == parse('foo{a="1"}').matchers
+
+a, b, c = parse('foo{a="1", b="2", c=~"3"}').matchers.matchers
+assert a.op == b.op == promql_parser.MatchOp.Equal and a.op != c.op
+by_op = {}
+for matcher in (a, b, c):
+    by_op.setdefault(matcher.op, []).append(matcher.name)
+assert by_op == {promql_parser.MatchOp.Equal: ['a', 'b'], promql_parser.MatchOp.Re: ['c']}
+
+expr = parse('sum(rate(http_requests_total[5m])) / max_over_time({__name__="http_requests_total"}[10m:1m]) + other')
+renamed = expr.rename_metric('http_requests_total', 'http_requests')
+assert str(renamed) == 'sum(rate(http_requests[5m])) / max_over_time({__name__="http_requests"}[10m:1m]) + other'
+assert str(expr).count('http_requests_total') == 2
+
+selectors = parse('rate(foo[5m]) + on(job) bar{job="api"}').vector_selectors()
+assert [str(vs) for vs in selectors] == ['foo', 'bar{job="api"}']
+assert all(isinstance(vs, promql_parser.VectorSelector) for vs in selectors)
+
+huge = parse('rate(foo[10000000y])').args[0]
+assert huge.range == timedelta.max
+assert str(huge) == 'foo[10000000y]'
+assert parse('foo offset -9000000y').offset == timedelta.min
+assert parse('foo offset 9000000y').offset == timedelta.max
+assert parse('max_over_time(foo[5m:] offset -9000000y)').args[0].offset == timedelta.min
+
+assert parse('foo').is_instant_vector() and not parse('foo').is_range_vector()
+assert parse('foo[5m]').is_range_vector() and not parse('foo[5m]').is_instant_vector()
+assert parse('rate(foo[5m])[10m:1m]').is_range_vector()
+assert parse('1 + 2').is_scalar() and parse('"up"').is_string()
+
+assert parse('histogram_quantile(0.9, sum by (le) (rate(foo_bucket[5m])))').uses_histogram_functions()
+assert parse('1 + histogram_count(rate(foo[5m]))').uses_histogram_functions()
+assert not parse('sum(rate(foo[5m]))').uses_histogram_functions()
+
+matchers = parse('foo{a="1", b=~"x.*", c="3", d!~"y"}').matchers
+assert [m.name for m in matchers.by_op(promql_parser.MatchOp.Equal)] == ['a', 'c']
+assert [m.name for m in matchers.by_op(promql_parser.MatchOp.Re)] == ['b']
+assert matchers.by_op(promql_parser.MatchOp.NotEqual) == []
+
+vs = promql_parser.VectorSelector('metric', offset=timedelta(minutes=-5))
+assert str(vs) == 'metric offset -5m' and vs.offset == timedelta(minutes=-5)
+at = parse('foo @ start()').at
+vs = promql_parser.VectorSelector('bar', parse('foo{job="api"}').matchers, offset=timedelta(0), at=at)
+assert str(vs) == 'bar{job="api"} @ start()' and vs.offset is None
+try:
+    promql_parser.VectorSelector()
+except ValueError:
+    pass
+else:
+    raise AssertionError('empty selector accepted')
+
+expr = parse('sum(rate(foo[5m])) + max_over_time(bar[10m:1m])')
+assert expr.any(lambda n: isinstance(n, promql_parser.SubqueryExpr))
+assert not parse('sum(foo)').any(lambda n: isinstance(n, promql_parser.SubqueryExpr))
+assert str(expr.find_first(lambda n: isinstance(n, promql_parser.VectorSelector))) == 'foo'
+assert expr.find_first(lambda n: isinstance(n, promql_parser.StringLiteral)) is None
+visited = []
+parse('a + b').find_first(lambda n: visited.append(str(n)) or str(n) == 'a')
+assert visited == ['a + b', 'a']
+expr = parse('a + b')
+assert expr.find_first(lambda n: str(n) == 'b') is expr.rhs
+expr = parse('rate(foo[5m])')
+assert expr.find_first(lambda n: isinstance(n, promql_parser.VectorSelector)) is expr.args[0].vector_selector
+assert not parse(' + '.join(['foo'] * 2000)).any(lambda n: False)
+
+assert str(parse('foo > 1000')) == 'foo > 1000'
+assert str(parse('foo > 0x10')) == str(parse('foo > 1.6e1')) == 'foo > 16'
+assert str(parse('foo > 1e300')) == 'foo > 1e300'
+assert str(parse('foo * 1.5e-7')) == 'foo * 1.5e-7'
+assert str(parse('foo * 0.001')) == 'foo * 0.001'
+
+at = promql_parser.AtModifier.from_datetime(datetime(2023, 11, 14, 22, 13, 20, tzinfo=timezone.utc))
+assert at.type == promql_parser.AtModifierType.At and at.timestamp() == 1700000000.0
+assert str(promql_parser.VectorSelector('foo', at=at)) == 'foo @ 1700000000.000'
+assert promql_parser.AtModifier.start().type == promql_parser.AtModifierType.Start
+assert str(promql_parser.VectorSelector('foo', at=promql_parser.AtModifier.end())) == 'foo @ end()'
+try:
+    promql_parser.AtModifier.from_datetime(datetime(2023, 11, 14))
+except ValueError:
+    pass
+else:
+    raise AssertionError('naive datetime accepted')
+for call in (promql_parser.AtModifier.from_datetime, parse('foo').with_at):
+    try:
+        call(datetime(1969, 12, 31, tzinfo=timezone.utc))
+    except ValueError:
+        pass
+    else:
+        raise AssertionError('pre-epoch datetime accepted')
+assert promql_parser.AtModifier.from_datetime(datetime(1970, 1, 1, tzinfo=timezone.utc)).timestamp() == 0.0
+
+assert parse('foo > -0') == parse('foo > 0')
+assert hash(parse('foo > -0')) == hash(parse('foo > 0'))
@@ -0,0 +1,19 @@
+use promql::parser::Expr;
+use promql_parser::expr::{PyExpr, Query};
+use pyo3::prelude::*;
+use pyo3::types::PyString;
+
+#[test]
+fn as_expr_borrows_the_parsed_tree() -> PyResult<()> {
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        let input = Query::Str(PyString::new(py, "sum(rate(foo[5m]))").extract()?);
+        let obj = PyExpr::parse(py, input, None, None, false)?;
+        let node = obj.bind(py).downcast::<PyExpr>()?;
+        let Expr::Aggregate(agg) = node.get().as_expr() else {
+            panic!("expected an aggregation, got {:?}", node.get().as_expr());
+        };
+        assert_eq!(agg.expr.to_string(), "rate(foo[5m])");
+        Ok(())
+    })
+}